@@ -0,0 +1,102 @@
+//! Named profiles, read from a `bidiff.toml` like:
+//!
+//! ```toml
+//! [profile.ci]
+//! preset = "balanced"
+//! method = "zstd"
+//! sort_partitions = 4
+//! merge_controls = true
+//! deadline = { secs = 30, nanos = 0 }
+//! anchors = [{ old_offset = 4096, new_offset = 8192 }]
+//! ```
+//!
+//! Besides `preset` and `method`, profiles hold [DiffParams] fields, with
+//! the same names and values as when deserializing them. Every field is
+//! optional. Options given on the command line take precedence over the
+//! profile, and the profile over its preset.
+
+use crate::{Method, Preset};
+use anyhow::{Context, Result};
+use bidiff::DiffParams;
+use serde::Deserialize;
+use std::{collections::BTreeMap, fs, path::Path};
+use toml::{Table, Value};
+
+/// Where profiles are read from when `--config` isn't given
+pub const DEFAULT_CONFIG: &str = "bidiff.toml";
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    #[serde(default)]
+    profile: BTreeMap<String, Profile>,
+}
+
+/// A named set of options, see the module documentation
+#[derive(Debug, Default, Deserialize)]
+pub struct Profile {
+    pub preset: Option<Preset>,
+    pub method: Option<Method>,
+    /// everything else, as [DiffParams] fields. They're kept as they are,
+    /// to be layered over the preset's, and checked when loading.
+    #[serde(flatten)]
+    params: Table,
+}
+
+impl Profile {
+    /// Read profile `name` from the config file at `path`, or from
+    /// [DEFAULT_CONFIG]. Without a name, nothing is read and the profile
+    /// is empty.
+    pub fn load(path: Option<&Path>, name: Option<&str>) -> Result<Self> {
+        let name = match name {
+            Some(name) => name,
+            None => return Ok(Self::default()),
+        };
+        let path = path.unwrap_or_else(|| Path::new(DEFAULT_CONFIG));
+        let contents =
+            fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+        let mut config: Config =
+            toml::from_str(&contents).with_context(|| format!("parse {}", path.display()))?;
+        let profile = config.profile.remove(name).with_context(|| {
+            format!(
+                "no profile {:?} in {} (found: {})",
+                name,
+                path.display(),
+                config
+                    .profile
+                    .keys()
+                    .map(|k| k.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        })?;
+        profile
+            .diff_params(&DiffParams::default(), None, None)
+            .with_context(|| format!("profile {:?} in {}", name, path.display()))?;
+        Ok(profile)
+    }
+
+    /// Layer the profile's diff params over `params` (the preset's), and
+    /// the options given on the command line over those.
+    pub fn diff_params(
+        &self,
+        params: &DiffParams,
+        sort_partitions: Option<usize>,
+        scan_chunk_size: Option<usize>,
+    ) -> Result<DiffParams> {
+        let mut table = Table::try_from(params)?;
+        table.extend(self.params.clone());
+        let overrides = [
+            ("sort_partitions", sort_partitions),
+            ("scan_chunk_size", scan_chunk_size),
+        ];
+        for (key, value) in overrides {
+            if let Some(value) = value {
+                table.insert(key.to_owned(), Value::Integer(value as i64));
+            }
+        }
+        Value::Table(table)
+            .try_into()
+            .context("invalid diff parameters")
+    }
+}
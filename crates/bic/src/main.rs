@@ -1,21 +1,44 @@
 use anyhow::{Context, Result};
 use argh::FromArgs;
 use bidiff::DiffParams;
+use bipatch::DecodeError;
 use comde::{Compressor, Decompressor};
+use config::Profile;
 use crossbeam_utils::thread;
 use log::*;
+use partial::PartialFile;
+use serde::Deserialize;
 use size::Size;
 use std::{
+    error::Error as StdError,
+    fmt,
     fs::{self, File},
     io::{self, BufReader, BufWriter, Read, Seek, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
     str::FromStr,
     time::Instant,
 };
 
+mod config;
+mod partial;
+mod priority;
+
 /// Generate and apply binary patches
 #[derive(FromArgs, PartialEq, Debug)]
+#[argh(
+    note = "Exit codes:\n  0    success\n  1    other errors\n  2    invalid arguments\n  3    I/O error\n  4    not a bidiff patch (wrong magic or version)\n  5    corrupt patch, or a patch for a different older file\n  6    cycle: patched output doesn't match the newer file\n  130  interrupted by Ctrl-C\n\nOutput files are written to a temporary file next to them, and only\nreplaced once complete."
+)]
 struct Cli {
+    /// only print errors
+    #[argh(switch, short = 'q')]
+    quiet: bool,
+    /// config file to read `--profile` from (default: bidiff.toml)
+    #[argh(option)]
+    config: Option<PathBuf>,
+    /// run at the lowest CPU and I/O priority, to keep the machine
+    /// responsive while diffing
+    #[argh(switch)]
+    background: bool,
     #[argh(subcommand)]
     cmd: Command,
 }
@@ -30,62 +53,118 @@ enum Command {
 
 /// Write the diff of two files to a patch file
 #[derive(FromArgs, PartialEq, Debug)]
-#[argh(subcommand, name = "diff")]
+#[argh(
+    subcommand,
+    name = "diff",
+    example = "Make a small zstd-compressed patch:\n$ {command_name} app-1.0 app-1.1 app.patch --preset balanced",
+    example = "Use the options of the [profile.ci] table in bidiff.toml:\n$ {command_name} app-1.0 app-1.1 app.patch --profile ci",
+    note = "The patch must be applied with the same compression method (or preset, or profile) it was made with."
+)]
 struct Diff {
+    /// the file to diff against
     #[argh(positional)]
     older: PathBuf,
+    /// the file the patch should produce
     #[argh(positional)]
     newer: PathBuf,
+    /// where to write the patch
     #[argh(positional)]
     patch: PathBuf,
-    /// number of partitions
-    #[argh(option, default = "1")]
-    sort_partitions: usize,
-    /// compression method to use
-    #[argh(option, default = "Method::Stored")]
-    method: Method,
-    /// optionally specify a chunk size
+    /// profile from the config file to take options from
+    #[argh(option)]
+    profile: Option<String>,
+    /// preset for the options below: fast, balanced or small
     #[argh(option)]
+    preset: Option<Preset>,
+    /// number of partitions to sort the older file in (default: 1)
+    #[argh(option, from_str_fn(at_least_one))]
+    sort_partitions: Option<usize>,
+    /// compression method: stored, deflate, brotli, snappy or zstd
+    /// (default: stored)
+    #[argh(option)]
+    method: Option<Method>,
+    /// size of the chunks the newer file is scanned in, in bytes
+    /// (default: a single chunk)
+    #[argh(option, from_str_fn(at_least_one))]
     scan_chunk_size: Option<usize>,
 }
 
 /// Apply a patch file generated by this tool
 #[derive(FromArgs, PartialEq, Debug)]
-#[argh(subcommand, name = "patch")]
+#[argh(
+    subcommand,
+    name = "patch",
+    example = "Apply a patch made with the balanced preset:\n$ {command_name} app-1.0 app.patch app-1.1 --preset balanced"
+)]
 struct Patch {
+    /// the file the patch was made against
     #[argh(positional)]
     older: PathBuf,
+    /// the patch to apply
     #[argh(positional)]
     patch: PathBuf,
+    /// where to write the patched file
     #[argh(positional)]
     output: PathBuf,
-    /// compression method to use
-    #[argh(option, default = "Method::Stored")]
-    method: Method,
+    /// profile from the config file the patch was made with
+    #[argh(option)]
+    profile: Option<String>,
+    /// preset the patch was made with, which sets the compression method
+    #[argh(option)]
+    preset: Option<Preset>,
+    /// compression method the patch was made with: stored, deflate,
+    /// brotli, snappy or zstd (default: stored)
+    #[argh(option)]
+    method: Option<Method>,
 }
 
-/// Cycle
+/// Diff two files and apply the patch in memory, then report the patch
+/// size and timings
 #[derive(FromArgs, PartialEq, Debug)]
-#[argh(subcommand, name = "cycle")]
+#[argh(
+    subcommand,
+    name = "cycle",
+    example = "Compare presets:\n$ {command_name} app-1.0 app-1.1 --preset fast\n$ {command_name} app-1.0 app-1.1 --preset small"
+)]
 struct Cycle {
+    /// the file to diff against
     #[argh(positional)]
     older: PathBuf,
+    /// the file the patch should produce
     #[argh(positional)]
     newer: PathBuf,
-    /// number of partitions
-    #[argh(option, default = "1")]
-    sort_partitions: usize,
-    /// compression method to use
-    #[argh(option, default = "Method::Stored")]
-    method: Method,
-    /// optionally specify a chunk size
+    /// profile from the config file to take options from
+    #[argh(option)]
+    profile: Option<String>,
+    /// preset for the options below: fast, balanced or small
+    #[argh(option)]
+    preset: Option<Preset>,
+    /// number of partitions to sort the older file in (default: 1)
+    #[argh(option, from_str_fn(at_least_one))]
+    sort_partitions: Option<usize>,
+    /// compression method: stored, deflate, brotli, snappy or zstd
+    /// (default: stored)
     #[argh(option)]
+    method: Option<Method>,
+    /// size of the chunks the newer file is scanned in, in bytes
+    /// (default: a single chunk)
+    #[argh(option, from_str_fn(at_least_one))]
     scan_chunk_size: Option<usize>,
 }
 
+fn at_least_one(value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(0) => Err("must be at least 1".into()),
+        Ok(n) => Ok(n),
+        Err(_) => Err(format!("expected a positive integer, got {:?}", value)),
+    }
+}
+
 /// Compression method used
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Method {
+    #[default]
     Stored,
     Deflate,
     Brotli,
@@ -93,12 +172,6 @@ pub enum Method {
     Zstd,
 }
 
-impl Default for Method {
-    fn default() -> Self {
-        Self::Stored
-    }
-}
-
 impl Method {
     fn compress<W: Write + Seek, R: Read>(
         self,
@@ -134,27 +207,262 @@ impl FromStr for Method {
             "brotli" => Ok(Method::Brotli),
             "snappy" => Ok(Method::Snappy),
             "zstd" => Ok(Method::Zstd),
-            _ => Err(format!("Unknown compression method {}", s)),
+            _ => Err(format!(
+                "unknown compression method {:?}, expected one of stored, deflate, brotli, snappy or zstd",
+                s
+            )),
         }
     }
 }
 
-fn main() -> Result<()> {
-    #[cfg(debug_assertions)]
-    std::env::set_var("RUST_BACKTRACE", "1");
+/// Sets of options for common tradeoffs between diffing speed and patch
+/// size. Options given explicitly take precedence.
+///
+/// Compression levels are fixed by comde: zstd uses level 21 and brotli
+/// quality 11, so the codec is what trades speed for size here.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Preset {
+    /// Sort and scan in parallel on all cores, skip through dissimilar data
+    /// and compress with snappy. Patches are about twice as large.
+    Fast,
+    /// Sort and scan in parallel, in large chunks, and compress with zstd.
+    /// Patches are a few percent larger than with `small`.
+    Balanced,
+    /// Single-threaded, unchunked, and compressed with brotli. The
+    /// smallest patches, and by far the slowest.
+    Small,
+}
+
+impl Preset {
+    fn method(self) -> Method {
+        match self {
+            Self::Fast => Method::Snappy,
+            Self::Balanced => Method::Zstd,
+            Self::Small => Method::Brotli,
+        }
+    }
+
+    fn sort_partitions(self) -> usize {
+        // leave a core for translating and compressing
+        let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+        match self {
+            Self::Fast => std::cmp::max(1, cores - 1),
+            Self::Balanced => (cores - 1).clamp(1, 4),
+            Self::Small => 1,
+        }
+    }
 
+    fn scan_chunk_size(self) -> Option<usize> {
+        match self {
+            Self::Fast => Some(512 * 1024),
+            Self::Balanced => Some(4 * 1024 * 1024),
+            Self::Small => None,
+        }
+    }
+
+    fn diff_params(self, params: DiffParams) -> DiffParams {
+        match self {
+            Self::Fast => params
+                .fast_identical_regions(true)
+                .skip_dissimilar(true)
+                .merge_controls(true),
+            Self::Balanced => params.fast_identical_regions(true).merge_controls(true),
+            Self::Small => params,
+        }
+    }
+}
+
+impl FromStr for Preset {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fast" => Ok(Preset::Fast),
+            "balanced" => Ok(Preset::Balanced),
+            "small" => Ok(Preset::Small),
+            _ => Err(format!(
+                "unknown preset {:?}, expected one of fast, balanced or small",
+                s
+            )),
+        }
+    }
+}
+
+/// Resolve the compression method from explicit options and the preset
+fn method(method: Option<Method>, preset: Option<Preset>) -> Method {
+    method
+        .or_else(|| preset.map(Preset::method))
+        .unwrap_or_default()
+}
+
+/// Build diff params from explicit options, the profile and the preset
+fn diff_params(
+    profile: &Profile,
+    preset: Option<Preset>,
+    sort_partitions: Option<usize>,
+    scan_chunk_size: Option<usize>,
+) -> Result<DiffParams> {
+    let params = match preset {
+        Some(preset) => preset.diff_params(
+            DiffParams::new(preset.sort_partitions(), preset.scan_chunk_size())
+                .map_err(|e| anyhow::anyhow!("invalid diff parameters: {}", e))?,
+        ),
+        None => DiffParams::default(),
+    };
+    profile.diff_params(&params, sort_partitions, scan_chunk_size)
+}
+
+/// Pick the error that explains a failure best, out of the errors returned
+/// by two threads connected by a pipe. When one side fails, the other
+/// usually just sees a broken pipe or a truncated stream.
+fn pipe_result<T>(main: Result<T>, worker: Result<()>) -> Result<T> {
+    match (main, worker) {
+        (Err(main), Err(worker)) => {
+            let broken_pipe = worker
+                .root_cause()
+                .downcast_ref::<io::Error>()
+                .is_some_and(|e| e.kind() == io::ErrorKind::BrokenPipe);
+            Err(if broken_pipe { main } else { worker })
+        }
+        (main, worker) => {
+            worker?;
+            main
+        }
+    }
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>> {
+    fs::read(path).with_context(|| format!("read {}", path.display()))
+}
+
+/// Decompressing the patch failed
+#[derive(Debug)]
+struct DecompressError(io::Error);
+
+impl fmt::Display for DecompressError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "decompress")
+    }
+}
+
+impl StdError for DecompressError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        Some(&self.0)
+    }
+}
+
+/// The patched output doesn't match the newer file
+#[derive(Debug)]
+struct HashMismatch;
+
+impl fmt::Display for HashMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "hash mismatch")
+    }
+}
+
+impl StdError for HashMismatch {}
+
+const EXIT_ERROR: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_IO: i32 = 3;
+const EXIT_NOT_A_PATCH: i32 = 4;
+const EXIT_CORRUPT_PATCH: i32 = 5;
+const EXIT_HASH_MISMATCH: i32 = 6;
+
+/// Exit code for an error, see the exit codes listed in `--help`
+fn exit_code(e: &anyhow::Error) -> i32 {
+    for cause in e.chain() {
+        if cause.is::<HashMismatch>() {
+            return EXIT_HASH_MISMATCH;
+        }
+        if cause.is::<DecompressError>() {
+            return EXIT_CORRUPT_PATCH;
+        }
+        if let Some(e) = cause.downcast_ref::<DecodeError>() {
+            return match e {
+                DecodeError::IO(e) => io_exit_code(e),
+                DecodeError::WrongMagic(_) | DecodeError::WrongVersion(_) => EXIT_NOT_A_PATCH,
+                _ => EXIT_CORRUPT_PATCH,
+            };
+        }
+        if let Some(e) = cause.downcast_ref::<io::Error>() {
+            return io_exit_code(e);
+        }
+    }
+    EXIT_ERROR
+}
+
+fn io_exit_code(e: &io::Error) -> i32 {
+    match e.kind() {
+        // bipatch reports invalid controls as `InvalidData`, and a patch
+        // that is truncated, or made for a longer older file, as
+        // `UnexpectedEof`
+        io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => EXIT_CORRUPT_PATCH,
+        _ => EXIT_IO,
+    }
+}
+
+fn main() {
     env_logger::builder().init();
 
-    let Cli { cmd } = argh::from_env();
+    let strings: Vec<String> = std::env::args().collect();
+    let cmd = Path::new(&strings[0])
+        .file_name()
+        .and_then(|s| s.to_str())
+        .unwrap_or("bic");
+    let strs: Vec<&str> = strings.iter().map(|s| s.as_str()).collect();
+    let cli = Cli::from_args(&[cmd], &strs[1..]).unwrap_or_else(|early_exit| {
+        // like `argh::from_env`, but with a distinct exit code for
+        // invalid arguments
+        std::process::exit(match early_exit.status {
+            Ok(()) => {
+                println!("{}", early_exit.output);
+                0
+            }
+            Err(()) => {
+                eprintln!(
+                    "{}\nRun {} --help for more information.",
+                    early_exit.output, cmd
+                );
+                EXIT_USAGE
+            }
+        })
+    });
+
+    if let Err(e) = partial::handle_interrupt().and_then(|_| run(cli)) {
+        // `{:#}` prints the whole chain of causes on one line, without the
+        // backtrace anyhow's `Debug` output includes
+        eprintln!("Error: {:#}", e);
+        std::process::exit(exit_code(&e));
+    }
+}
+
+fn run(
+    Cli {
+        quiet,
+        config,
+        background,
+        cmd,
+    }: Cli,
+) -> Result<()> {
+    if background {
+        priority::lower().context("lower priority")?;
+    }
+
+    let config = config.as_deref();
     match cmd {
         Command::Diff(args) => {
-            do_diff(&args)?;
+            let profile = Profile::load(config, args.profile.as_deref())?;
+            do_diff(&args, &profile, quiet)?;
         }
         Command::Patch(args) => {
-            do_patch(&args)?;
+            let profile = Profile::load(config, args.profile.as_deref())?;
+            do_patch(&args, &profile, quiet)?;
         }
         Command::Cycle(args) => {
-            do_cycle(&args)?;
+            let profile = Profile::load(config, args.profile.as_deref())?;
+            do_cycle(&args, &profile)?;
         }
     }
 
@@ -165,13 +473,20 @@ fn do_cycle(
     Cycle {
         older,
         newer,
+        profile: _,
+        preset,
         method,
         sort_partitions,
         scan_chunk_size,
     }: &Cycle,
+    profile: &Profile,
 ) -> Result<()> {
+    let preset = preset.or(profile.preset);
+    let method = self::method(method.or(profile.method), preset);
+    let diff_params = diff_params(profile, preset, *sort_partitions, *scan_chunk_size)?;
+
     info!("Reading older and newer in memory...");
-    let (older, newer) = (fs::read(older)?, fs::read(newer)?);
+    let (older, newer) = (read_file(older)?, read_file(newer)?);
 
     info!(
         "Before {}, After {}",
@@ -187,26 +502,27 @@ fn do_cycle(
 
         let (mut patch_r, mut patch_w) = pipe::pipe();
         thread::scope(|s| {
-            s.spawn(|_| {
-                bidiff::simple_diff_with_params(
+            let differ = s.spawn(|_| {
+                let res = bidiff::simple_diff_with_params(
                     &older[..],
                     &newer[..],
                     &mut patch_w,
-                    &DiffParams::new(*sort_partitions, *scan_chunk_size).unwrap(),
+                    &diff_params,
                 )
-                .context("simple diff with params")
-                .unwrap();
+                .context("simple diff with params");
                 // this is important for `.compress()` to finish.
                 // since we're using scoped threads, it's never dropped
                 // otherwise.
                 drop(patch_w);
+                res
             });
-            method
+            let res = method
                 .compress(&mut compatch_w, &mut patch_r)
-                .context("compress")
-                .unwrap();
+                .context("compress");
+            drop(patch_r);
+            pipe_result(res, differ.join().expect("diff thread panicked"))
         })
-        .unwrap();
+        .expect("diff thread panicked")?;
     }
 
     let diff_duration = before_diff.elapsed();
@@ -221,28 +537,28 @@ fn do_cycle(
         let (patch_r, patch_w) = pipe::pipe();
 
         thread::scope(|s| {
-            s.spawn(|_| {
+            let decompressor = s.spawn(|_| {
                 method
                     .decompress(&compatch[..], patch_w)
-                    .context("decompress")
-                    .unwrap();
+                    .map(|_| ())
+                    .map_err(|e| DecompressError(e).into())
             });
 
-            let mut r = bipatch::Reader::new(patch_r, &mut older)
+            let res = bipatch::Reader::new(patch_r, &mut older)
                 .context("read patch")
-                .unwrap();
-            let fresh_size = io::copy(&mut r, &mut fresh).unwrap();
-
-            assert_eq!(fresh_size as usize, newer.len());
+                .and_then(|mut r| io::copy(&mut r, &mut fresh).context("apply patch"));
+            pipe_result(res, decompressor.join().expect("patch thread panicked"))
         })
-        .unwrap();
+        .expect("patch thread panicked")?;
     }
     let patch_duration = before_patch.elapsed();
 
     let newer_hash = hmac_sha256::Hash::hash(&newer[..]);
     let fresh_hash = hmac_sha256::Hash::hash(&fresh[..]);
 
-    anyhow::ensure!(newer_hash == fresh_hash, "Hash mismatch!");
+    if newer_hash != fresh_hash {
+        return Err(HashMismatch.into());
+    }
 
     let cm = format!("{:?}", method);
     let cp = format!("patch {}", Size::from_bytes(compatch.len()));
@@ -263,27 +579,42 @@ fn do_patch(
         older,
         patch,
         output,
+        profile: _,
+        preset,
         method,
     }: &Patch,
+    profile: &Profile,
+    quiet: bool,
 ) -> Result<()> {
-    println!("Using method {:?}", method);
+    let method = self::method(method.or(profile.method), preset.or(profile.preset));
+    if !quiet {
+        println!("Using method {:?}", method);
+    }
     let start = Instant::now();
 
-    let compatch_r = BufReader::new(File::open(patch).context("open patch file")?);
-    let (patch_r, patch_w) = pipe::pipe();
-    let method = *method;
+    let compatch_r =
+        BufReader::new(File::open(patch).with_context(|| format!("open {}", patch.display()))?);
+    let older_r = File::open(older).with_context(|| format!("open {}", older.display()))?;
+    let output_file = PartialFile::create(output)?;
+    let mut output_w = BufWriter::new(output_file.file());
 
-    std::thread::spawn(move || {
+    let (patch_r, patch_w) = pipe::pipe();
+    let decompressor = std::thread::spawn(move || {
         method
             .decompress(compatch_r, patch_w)
-            .context("decompress")
-            .unwrap();
+            .map(|_| ())
+            .map_err(|e| DecompressError(e).into())
     });
 
-    let older_r = File::open(older)?;
-    let mut fresh_r = bipatch::Reader::new(patch_r, older_r).context("read patch")?;
-    let mut output_w = BufWriter::new(File::create(output).context("create patch file")?);
-    io::copy(&mut fresh_r, &mut output_w).context("write output file")?;
+    let res = bipatch::Reader::new(patch_r, older_r)
+        .context("read patch")
+        .and_then(|mut fresh_r| {
+            io::copy(&mut fresh_r, &mut output_w).context("apply patch")?;
+            output_w.flush().context("finish writing output file")
+        });
+    pipe_result(res, decompressor.join().expect("patch thread panicked"))?;
+    drop(output_w);
+    output_file.persist()?;
 
     info!("Completed in {:?}", start.elapsed());
 
@@ -295,20 +626,30 @@ fn do_diff(
         older,
         newer,
         patch,
+        profile: _,
+        preset,
         method,
         sort_partitions,
         scan_chunk_size,
     }: &Diff,
+    profile: &Profile,
+    quiet: bool,
 ) -> Result<()> {
-    println!("Using method {:?}", method);
+    let preset = preset.or(profile.preset);
+    let method = self::method(method.or(profile.method), preset);
+    let diff_params = diff_params(profile, preset, *sort_partitions, *scan_chunk_size)?;
+    if !quiet {
+        println!("Using method {:?}", method);
+    }
     let start = Instant::now();
 
-    let older_contents = fs::read(older).context("read old file")?;
-    let newer_contents = fs::read(newer).context("read new file")?;
+    let older_contents = read_file(older)?;
+    let newer_contents = read_file(newer)?;
+    let patch_file = PartialFile::create(patch)?;
+    let mut compatch_w = BufWriter::new(patch_file.file());
 
     let (mut patch_r, mut patch_w) = pipe::pipe();
-    let diff_params = DiffParams::new(*sort_partitions, *scan_chunk_size).unwrap();
-    std::thread::spawn(move || {
+    let differ = std::thread::spawn(move || {
         bidiff::simple_diff_with_params(
             &older_contents[..],
             &newer_contents[..],
@@ -316,14 +657,16 @@ fn do_diff(
             &diff_params,
         )
         .context("simple diff with params")
-        .unwrap();
     });
 
-    let mut compatch_w = BufWriter::new(File::create(patch).context("create patch file")?);
-    method
+    let res = method
         .compress(&mut compatch_w, &mut patch_r)
-        .context("write output file")?;
-    compatch_w.flush().context("finish writing output file")?;
+        .context("write output file")
+        .and_then(|_| compatch_w.flush().context("finish writing output file"));
+    drop(patch_r);
+    pipe_result(res, differ.join().expect("diff thread panicked"))?;
+    drop(compatch_w);
+    patch_file.persist()?;
 
     info!("Completed in {:?}", start.elapsed());
 
@@ -0,0 +1,128 @@
+//! Writing output files so that a failed or interrupted run doesn't leave a
+//! truncated one behind.
+//!
+//! Output goes to a temporary file next to the destination, which is
+//! renamed over it once complete. The temporary file is removed on error,
+//! and on Ctrl-C by the handler [handle_interrupt] installs.
+
+use anyhow::{Context, Result};
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// Exit code for a run interrupted by Ctrl-C, the shell's usual code for
+/// SIGINT
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+/// Temporary files that haven't been renamed or removed yet
+static PENDING: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// On Ctrl-C, remove pending temporary files and exit with
+/// [EXIT_INTERRUPTED].
+pub fn handle_interrupt() -> Result<()> {
+    ctrlc::set_handler(|| {
+        // the lock is held until exit, so no file is added or renamed after
+        // it's been removed
+        let pending = PENDING.lock().unwrap_or_else(|e| e.into_inner());
+        for path in pending.iter() {
+            let _ = fs::remove_file(path);
+        }
+        eprintln!("Interrupted");
+        std::process::exit(EXIT_INTERRUPTED);
+    })
+    .context("install Ctrl-C handler")
+}
+
+/// An output file being written
+pub struct PartialFile {
+    file: Option<File>,
+    temp: PathBuf,
+    dest: PathBuf,
+}
+
+impl PartialFile {
+    /// Start writing what will become `dest`
+    pub fn create(dest: &Path) -> Result<Self> {
+        let name = dest
+            .file_name()
+            .with_context(|| format!("create {}: not a file name", dest.display()))?;
+        let mut temp_name = std::ffi::OsString::from(".");
+        temp_name.push(name);
+        temp_name.push(format!(".bic-{}.tmp", std::process::id()));
+        let temp = dest.with_file_name(temp_name);
+
+        let mut pending = PENDING.lock().unwrap();
+        let file = File::create(&temp).with_context(|| format!("create {}", temp.display()))?;
+        pending.push(temp.clone());
+        Ok(Self {
+            file: Some(file),
+            temp,
+            dest: dest.to_owned(),
+        })
+    }
+
+    pub fn file(&self) -> &File {
+        self.file.as_ref().expect("file used after persist")
+    }
+
+    /// Replace the destination with what's been written
+    pub fn persist(mut self) -> Result<()> {
+        let file = self.file.take().expect("persisted twice");
+        file.sync_all()
+            .with_context(|| format!("sync {}", self.temp.display()))?;
+        drop(file);
+
+        let mut pending = PENDING.lock().unwrap();
+        fs::rename(&self.temp, &self.dest).with_context(|| {
+            format!("rename {} to {}", self.temp.display(), self.dest.display())
+        })?;
+        pending.retain(|p| *p != self.temp);
+        Ok(())
+    }
+}
+
+impl Drop for PartialFile {
+    /// Remove the temporary file, unless it was renamed
+    fn drop(&mut self) {
+        let mut pending = PENDING.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(i) = pending.iter().position(|p| *p == self.temp) {
+            pending.remove(i);
+            drop(self.file.take());
+            let _ = fs::remove_file(&self.temp);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn persist_or_remove() {
+        let dir = std::env::temp_dir().join(format!("bic-partial-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let dest = dir.join("out.bin");
+        fs::write(&dest, b"previous").unwrap();
+
+        // dropped without persisting: the destination is left alone
+        let partial = PartialFile::create(&dest).unwrap();
+        partial.file().write_all(b"trunc").unwrap();
+        drop(partial);
+        assert_eq!(fs::read(&dest).unwrap(), b"previous");
+
+        let partial = PartialFile::create(&dest).unwrap();
+        partial.file().write_all(b"complete").unwrap();
+        partial.persist().unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), b"complete");
+
+        let names: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["out.bin"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
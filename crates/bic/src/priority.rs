@@ -0,0 +1,86 @@
+//! Running at background priority, see `--background`.
+//!
+//! The priority is lowered for the whole process before any worker thread
+//! is started, so the threads of the diff (rayon's pool included) inherit
+//! it.
+
+use std::io;
+
+/// Lower the CPU priority to the lowest nice value, and use the idle I/O
+/// class, which only gets disk time nobody else wants.
+#[cfg(target_os = "linux")]
+pub fn lower() -> io::Result<()> {
+    // from linux/ioprio.h
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+    nice()?;
+    // SAFETY: ioprio_set only reads its integer arguments
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_ioprio_set,
+            IOPRIO_WHO_PROCESS,
+            0,
+            IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+        )
+    };
+    if res == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Put the process in the background band, which throttles both its CPU
+/// and its I/O.
+#[cfg(target_os = "macos")]
+pub fn lower() -> io::Result<()> {
+    // SAFETY: setpriority only reads its integer arguments
+    let res = unsafe { libc::setpriority(libc::PRIO_DARWIN_PROCESS, 0, libc::PRIO_DARWIN_BG) };
+    if res == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Lower the CPU priority to the lowest nice value. There's no portable way
+/// to lower I/O priority.
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "macos"))))]
+pub fn lower() -> io::Result<()> {
+    nice()
+}
+
+/// Enter background processing mode, which lowers CPU, I/O and memory
+/// priority.
+#[cfg(windows)]
+pub fn lower() -> io::Result<()> {
+    use windows_sys::Win32::System::Threading::{
+        GetCurrentProcess, SetPriorityClass, PROCESS_MODE_BACKGROUND_BEGIN,
+    };
+
+    // SAFETY: GetCurrentProcess returns a pseudo handle that needs no
+    // closing
+    let res = unsafe { SetPriorityClass(GetCurrentProcess(), PROCESS_MODE_BACKGROUND_BEGIN) };
+    if res == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn lower() -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "background priority is not supported on this platform",
+    ))
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn nice() -> io::Result<()> {
+    // SAFETY: setpriority only reads its integer arguments
+    let res = unsafe { libc::setpriority(libc::PRIO_PROCESS as _, 0, 19) };
+    if res == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,27 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use std::io::{Cursor, Read};
+
+/// Applying is where untrusted input comes in: arbitrary patches must
+/// produce errors, never panics or unbounded allocations.
+fuzz_target!(|data: &[u8]| {
+    if data.is_empty() {
+        return;
+    }
+
+    let (old_len, data) = (data[0] as usize * 16, &data[1..]);
+    let older: Vec<u8> = (0..old_len).map(|i| i as u8).collect();
+
+    // most random inputs would fail the header check, so always provide
+    // a valid one
+    let mut patch = Vec::with_capacity(8 + data.len());
+    patch.extend_from_slice(&bipatch::MAGIC.to_le_bytes());
+    patch.extend_from_slice(&bipatch::VERSION.to_le_bytes());
+    patch.extend_from_slice(data);
+
+    if let Ok(r) = bipatch::Reader::new(&patch[..], Cursor::new(&older[..])) {
+        // copy lengths can be huge, don't wait for them to run out
+        let mut fresh = Vec::new();
+        let _ = r.take(1024 * 1024).read_to_end(&mut fresh);
+    }
+});
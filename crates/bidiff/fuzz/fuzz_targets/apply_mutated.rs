@@ -0,0 +1,32 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use std::io::{Cursor, Read};
+
+/// Mutates valid patches, to reach deeper into the applier than random
+/// bytes usually do.
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 16 {
+        return;
+    }
+
+    let (mutations, data) = data.split_at(8);
+    let mid = data.len() / 2;
+    let (older, instr) = (&data[..mid], &data[mid..]);
+    let newer = bidiff::instructions::apply_instructions(older, instr);
+
+    let mut patch = Vec::new();
+    bidiff::simple_diff(older, &newer, &mut patch).unwrap();
+
+    // each pair of bytes xors one byte of the patch, past its header
+    for pair in mutations.chunks_exact(2) {
+        if patch.len() > 8 {
+            let pos = 8 + pair[0] as usize % (patch.len() - 8);
+            patch[pos] ^= pair[1];
+        }
+    }
+
+    if let Ok(r) = bipatch::Reader::new(&patch[..], Cursor::new(older)) {
+        let mut fresh = Vec::new();
+        let _ = r.take(1024 * 1024).read_to_end(&mut fresh);
+    }
+});
@@ -1,39 +1,79 @@
 use super::Control;
+use bipatch::ControlSeek;
 use byteorder::{LittleEndian, WriteBytesExt};
 use integer_encoding::VarIntWriter;
-use std::io::{self, Write};
+use std::{
+    convert::TryFrom,
+    error::Error as StdError,
+    fmt,
+    io::{self, Write},
+};
 
 pub const MAGIC: u32 = 0xB1DF;
-pub const VERSION: u32 = 0x1000;
+/// Patches start with [MAGIC] and this version, then the largest distance
+/// between the positions the older file is read from (see
+/// [DiffParams::max_backref_distance]), as a little-endian u64, 0 meaning
+/// there is no limit. Version 0x1000 patches had no such field.
+///
+/// Each control ends with a varint whose lowest bit tells what the rest
+/// is: a relative seek, zigzag encoded (0), or an absolute position in the
+/// older file (1), whichever is shorter. Up to version 0x1001, seeks were
+/// always relative, as a zigzag varint.
+///
+/// [DiffParams::max_backref_distance]: crate::DiffParams::max_backref_distance
+pub const VERSION: u32 = 0x1002;
 
 pub struct Writer<W>
 where
     W: Write,
 {
     w: W,
+    /// position in the older file
+    pos: u64,
 }
 
 impl<W> Writer<W>
 where
     W: Write,
 {
-    pub fn new(mut w: W) -> Result<Self, io::Error> {
+    pub fn new(w: W) -> Result<Self, io::Error> {
+        Self::with_max_backref_distance(w, None)
+    }
+
+    /// Like [Writer::new], but records in the header that the controls
+    /// written respect `max_backref_distance`, so appliers can tell how
+    /// much of the older file they need to keep around.
+    pub fn with_max_backref_distance(
+        mut w: W,
+        max_backref_distance: Option<usize>,
+    ) -> Result<Self, io::Error> {
         w.write_u32::<LittleEndian>(MAGIC)?;
         w.write_u32::<LittleEndian>(VERSION)?;
+        w.write_u64::<LittleEndian>(max_backref_distance.unwrap_or(0) as u64)?;
 
-        Ok(Self { w })
+        Ok(Self { w, pos: 0 })
     }
 
-    pub fn write(&mut self, c: &Control) -> Result<(), io::Error> {
+    /// Write a [Control], or a [ControlBuf](crate::ControlBuf)
+    pub fn write<'c, C>(&mut self, c: C) -> Result<(), io::Error>
+    where
+        C: Into<Control<'c>>,
+    {
+        let c = c.into();
         let w = &mut self.w;
 
-        w.write_varint(c.add.len())?;
+        // lengths are always encoded as u64 on the wire, regardless
+        // of the platform's pointer width
+        w.write_varint(c.add.len() as u64)?;
         w.write_all(c.add)?;
 
-        w.write_varint(c.copy.len())?;
+        w.write_varint(c.copy.len() as u64)?;
         w.write_all(c.copy)?;
 
-        w.write_varint(c.seek)?;
+        let from = self.pos + c.add.len() as u64;
+        let (seek, to) = encode_seek(from, c.seek)?;
+        w.write_varint(seek)?;
+        self.pos = to;
 
         Ok(())
     }
@@ -46,3 +86,83 @@ where
         self.w
     }
 }
+
+/// Encode a seek by `seek` bytes from `from` in the older file, the way
+/// [bipatch::ControlSeek::encode] does. Returns the encoded seek and the
+/// position it leads to.
+fn encode_seek(from: u64, seek: i64) -> Result<(u64, u64), io::Error> {
+    let to = i64::try_from(from)
+        .ok()
+        .and_then(|from| from.checked_add(seek))
+        .and_then(|to| u64::try_from(to).ok())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("seek by {} from {} leaves the older file", seek, from),
+            )
+        })?;
+
+    // `to` fits in an i64, so it can always be encoded as an absolute
+    // position
+    let encoded = ControlSeek::encode(from, seek).expect("seek within the older file");
+    Ok((encoded, to))
+}
+
+/// Returned (wrapped in an `io::Error`) by [CappedVec] once a patch grows
+/// past its cap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeCapExceeded {
+    pub cap: usize,
+}
+
+impl SizeCapExceeded {
+    /// Find out whether `e` was caused by a [CappedVec] exceeding its cap
+    pub fn from_io(e: &io::Error) -> Option<&Self> {
+        e.get_ref().and_then(|inner| inner.downcast_ref())
+    }
+}
+
+impl fmt::Display for SizeCapExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "patch exceeds size cap of {} bytes", self.cap)
+    }
+}
+
+impl StdError for SizeCapExceeded {}
+
+/// An in-memory sink that refuses to grow past `cap` bytes.
+///
+/// Writing past the cap fails with a [SizeCapExceeded] error, which
+/// aborts diffing early: there is no point finishing a patch that is
+/// already too large to be useful.
+pub struct CappedVec {
+    buf: Vec<u8>,
+    cap: usize,
+}
+
+impl CappedVec {
+    pub fn new(cap: usize) -> Self {
+        Self {
+            buf: Vec::new(),
+            cap,
+        }
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+impl Write for CappedVec {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.buf.len().saturating_add(buf.len()) > self.cap {
+            return Err(io::Error::other(SizeCapExceeded { cap: self.cap }));
+        }
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,231 @@
+//! Patches in the layout of Matthew Endsley's bsdiff (requires the `enc`
+//! feature), for appliers that can't read bidiff's own format.
+//!
+//! Endsley's `bspatch()`, and embedded appliers derived from it, read a
+//! bare series of controls, with no header or compression:
+//!
+//! - 8 bytes: length of the diff string (our ADD)
+//! - 8 bytes: length of the extra string (our COPY)
+//! - 8 bytes: how far to seek in the older file afterwards
+//! - the diff string, then the extra string
+//!
+//! Integers are little-endian, in sign-magnitude form. The applier stops
+//! once it has produced the newer file's length, which it has to be told
+//! separately: the `ENDSLEY/BSDIFF43` header that holds it is written by
+//! Endsley's command-line tool, around its bzip2 stream, not by `bsdiff()`.
+//!
+//! ```no_run
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let (older, newer) = (Vec::new(), Vec::new());
+//! let mut patch = Vec::new();
+//! bidiff::endsley::simple_diff(&older, &newer, &mut patch)?;
+//! // ship `patch` along with `newer.len()`
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{diff, Control, DiffParams, Translator};
+use std::io::{self, Write};
+
+/// Longest diff or extra string a control may hold: Endsley's `bspatch()`
+/// rejects anything longer than `INT_MAX`. Longer ones are split.
+pub const MAX_LEN: usize = i32::MAX as usize;
+
+/// Writes controls in Endsley's layout
+pub struct Writer<W>
+where
+    W: Write,
+{
+    w: W,
+    max_len: usize,
+}
+
+impl<W> Writer<W>
+where
+    W: Write,
+{
+    pub fn new(w: W) -> Self {
+        Self {
+            w,
+            max_len: MAX_LEN,
+        }
+    }
+
+    /// Write a [Control], or a [ControlBuf](crate::ControlBuf)
+    pub fn write<'c, C>(&mut self, c: C) -> Result<(), io::Error>
+    where
+        C: Into<Control<'c>>,
+    {
+        let c = c.into();
+        let mut add = c.add;
+        while add.len() > self.max_len {
+            let (head, tail) = add.split_at(self.max_len);
+            // the next add continues reading the older file where this
+            // one stops
+            self.write_control(head, &[], 0)?;
+            add = tail;
+        }
+
+        let mut copy = c.copy;
+        while copy.len() > self.max_len {
+            let (head, tail) = copy.split_at(self.max_len);
+            self.write_control(add, head, 0)?;
+            add = &[];
+            copy = tail;
+        }
+
+        self.write_control(add, copy, c.seek)
+    }
+
+    fn write_control(&mut self, add: &[u8], copy: &[u8], seek: i64) -> Result<(), io::Error> {
+        let w = &mut self.w;
+        w.write_all(&offtout(add.len() as i64))?;
+        w.write_all(&offtout(copy.len() as i64))?;
+        w.write_all(&offtout(seek))?;
+        w.write_all(add)?;
+        w.write_all(copy)?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), io::Error> {
+        self.w.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.w
+    }
+}
+
+/// Encode `x` the way bsdiff does: little-endian magnitude, with the sign
+/// in the top bit
+fn offtout(x: i64) -> [u8; 8] {
+    let magnitude = x.unsigned_abs();
+    let sign = if x < 0 { 1 << 63 } else { 0 };
+    (magnitude | sign).to_le_bytes()
+}
+
+/// Diff `older` and `newer` with default parameters, and write the patch
+/// to `out` in Endsley's layout.
+pub fn simple_diff(older: &[u8], newer: &[u8], out: &mut dyn Write) -> Result<(), io::Error> {
+    simple_diff_with_params(older, newer, out, &Default::default())
+}
+
+/// Like [crate::simple_diff_with_params], but writes the patch in
+/// Endsley's layout.
+pub fn simple_diff_with_params(
+    older: &[u8],
+    newer: &[u8],
+    out: &mut dyn Write,
+    diff_params: &DiffParams,
+) -> Result<(), io::Error> {
+    let mut w = Writer::new(out);
+
+    let mut translator = Translator::new(older, newer, |control| w.write(control))
+        .min_copy_len(diff_params.min_copy_len)
+        .merge_controls(diff_params.merge_controls);
+    diff(older, newer, diff_params, |m| translator.translate(m))?;
+    translator.close()?;
+
+    w.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instructions::{apply_edits, EditParams};
+
+    fn apply(older: &[u8], patch: &[u8]) -> Vec<u8> {
+        let mut fresh = Vec::new();
+        bsdiff::patch(older, &mut &patch[..], &mut fresh).unwrap();
+        fresh
+    }
+
+    #[test]
+    fn offtout_sign_magnitude() {
+        assert_eq!(offtout(0), [0; 8]);
+        assert_eq!(offtout(0x0102), [2, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(offtout(-1), [1, 0, 0, 0, 0, 0, 0, 0x80]);
+    }
+
+    #[test]
+    fn applies_with_bsdiff() {
+        let older = b"the quick brown fox jumps over the lazy dog".repeat(200);
+        let instructions: Vec<u8> = (0..200_u32).map(|i| (i * 37) as u8).collect();
+        let newer = apply_edits(&older, &instructions, &EditParams::default());
+
+        for params in [
+            DiffParams::default(),
+            DiffParams::new(2, Some(1000)).unwrap().merge_controls(true),
+        ] {
+            let mut patch = Vec::new();
+            simple_diff_with_params(&older, &newer, &mut patch, &params).unwrap();
+            assert_eq!(apply(&older, &patch), newer);
+        }
+
+        for (older, newer) in [(&b""[..], &b""[..]), (b"", b"fresh"), (b"stale", b"")] {
+            let mut patch = Vec::new();
+            simple_diff(older, newer, &mut patch).unwrap();
+            assert_eq!(apply(older, &patch), newer);
+        }
+    }
+
+    #[test]
+    fn long_strings_are_split() {
+        let older = b"0123456789".repeat(10);
+        let controls = [
+            Control {
+                add: &[0; 100],
+                copy: b"abcdefghijklmnopqrstuvwxyz",
+                seek: -100,
+            },
+            Control {
+                add: &[1; 15],
+                copy: &[],
+                seek: 50,
+            },
+            Control {
+                add: &[],
+                copy: b"12345678",
+                seek: 0,
+            },
+        ];
+        let mut newer = older.clone();
+        newer.extend_from_slice(b"abcdefghijklmnopqrstuvwxyz");
+        newer.extend(older[..15].iter().map(|b| b + 1));
+        newer.extend_from_slice(b"12345678");
+
+        let mut w = Writer::new(Vec::new());
+        w.max_len = 7;
+        for c in &controls {
+            w.write(c).unwrap();
+        }
+        let patch = w.into_inner();
+        assert_eq!(apply(&older, &patch), newer);
+
+        let offtin = |r: &mut &[u8]| {
+            let mut x = [0; 8];
+            x.copy_from_slice(&r[..8]);
+            *r = &r[8..];
+            let x = u64::from_le_bytes(x);
+            let magnitude = (x & !(1 << 63)) as i64;
+            if x >> 63 == 1 {
+                -magnitude
+            } else {
+                magnitude
+            }
+        };
+        let (mut add, mut copy, mut seek) = (0, 0, 0);
+        let mut r = &patch[..];
+        while !r.is_empty() {
+            let (add_len, copy_len) = (offtin(&mut r), offtin(&mut r));
+            assert!(add_len <= 7 && copy_len <= 7, "{} {}", add_len, copy_len);
+            seek += offtin(&mut r);
+            r = &r[(add_len + copy_len) as usize..];
+            add += add_len;
+            copy += copy_len;
+        }
+        assert_eq!(add, controls.iter().map(|c| c.add.len() as i64).sum());
+        assert_eq!(copy, controls.iter().map(|c| c.copy.len() as i64).sum());
+        assert_eq!(seek, controls.iter().map(|c| c.seek).sum());
+    }
+}
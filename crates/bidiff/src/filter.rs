@@ -0,0 +1,211 @@
+//! Transformations of control streams, chained between a [Translator] and
+//! a writer.
+//!
+//! Each [Filter] receives controls one at a time and passes any number of
+//! controls on, so it can hold some back, merge or split them. Filters are
+//! chained with [Filter::then]:
+//!
+//! ```no_run
+//! # fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+//! # let (older, newer, out) = (Vec::new(), Vec::new(), std::io::sink());
+//! use bidiff::{
+//!     enc,
+//!     filter::{Filter, Merge, Stats},
+//!     DiffParams, Translator,
+//! };
+//!
+//! let params = DiffParams::new(4, Some(64 * 1024))?;
+//! let mut w = enc::Writer::new(out)?;
+//! let mut stats = Stats::default();
+//! let mut filters = Merge::default().then(&mut stats);
+//!
+//! let mut translator = Translator::new(&older, &newer, |c| {
+//!     filters.filter(c.into(), &mut |c| w.write(&c))
+//! });
+//! bidiff::diff(&older, &newer, &params, |m| translator.translate(m))?;
+//! translator.close()?;
+//! filters.finish(&mut |c| w.write(&c))?;
+//! drop(filters);
+//!
+//! println!("{:?}", stats);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [Translator]: crate::Translator
+
+use crate::ControlBuf;
+use std::io;
+
+/// Where a [Filter] passes controls on to
+pub type Next<'a> = dyn FnMut(ControlBuf) -> io::Result<()> + 'a;
+
+pub trait Filter {
+    /// Handle a control, passing whatever should follow on to `next`.
+    fn filter(&mut self, c: ControlBuf, next: &mut Next<'_>) -> io::Result<()>;
+
+    /// Pass on any controls held back, after the last one.
+    fn finish(&mut self, next: &mut Next<'_>) -> io::Result<()> {
+        let _ = next;
+        Ok(())
+    }
+
+    /// Pass controls through `self`, then through `other`.
+    fn then<F>(self, other: F) -> Chain<Self, F>
+    where
+        Self: Sized,
+        F: Filter,
+    {
+        Chain(self, other)
+    }
+}
+
+impl<F> Filter for &mut F
+where
+    F: Filter + ?Sized,
+{
+    fn filter(&mut self, c: ControlBuf, next: &mut Next<'_>) -> io::Result<()> {
+        (**self).filter(c, next)
+    }
+
+    fn finish(&mut self, next: &mut Next<'_>) -> io::Result<()> {
+        (**self).finish(next)
+    }
+}
+
+/// Two filters, one after the other, see [Filter::then]
+pub struct Chain<A, B>(A, B);
+
+impl<A, B> Filter for Chain<A, B>
+where
+    A: Filter,
+    B: Filter,
+{
+    fn filter(&mut self, c: ControlBuf, next: &mut Next<'_>) -> io::Result<()> {
+        let Self(a, b) = self;
+        a.filter(c, &mut |c| b.filter(c, next))
+    }
+
+    fn finish(&mut self, next: &mut Next<'_>) -> io::Result<()> {
+        let Self(a, b) = self;
+        a.finish(&mut |c| b.filter(c, next))?;
+        b.finish(next)
+    }
+}
+
+/// Merges consecutive controls when that doesn't change the output, like
+/// [Translator::merge_controls] does with matches: controls without an
+/// add are appended to the previous copy, and controls that follow an add
+/// with neither a copy nor a seek extend that add.
+///
+/// [Translator::merge_controls]: crate::Translator::merge_controls
+#[derive(Debug, Default)]
+pub struct Merge {
+    held: Option<ControlBuf>,
+}
+
+impl Filter for Merge {
+    fn filter(&mut self, c: ControlBuf, next: &mut Next<'_>) -> io::Result<()> {
+        match self.held.as_mut() {
+            Some(p) if c.add.is_empty() => {
+                p.copy.extend_from_slice(&c.copy);
+                p.seek += c.seek;
+            }
+            Some(p) if p.copy.is_empty() && p.seek == 0 => {
+                p.add.extend_from_slice(&c.add);
+                p.copy = c.copy;
+                p.seek = c.seek;
+            }
+            _ => {
+                if let Some(p) = self.held.replace(c) {
+                    next(p)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, next: &mut Next<'_>) -> io::Result<()> {
+        match self.held.take() {
+            Some(p) => next(p),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Passes controls on unchanged, counting them
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    pub controls: u64,
+    pub add_bytes: u64,
+    pub copy_bytes: u64,
+}
+
+impl Filter for Stats {
+    fn filter(&mut self, c: ControlBuf, next: &mut Next<'_>) -> io::Result<()> {
+        self.controls += 1;
+        self.add_bytes += c.add.len() as u64;
+        self.copy_bytes += c.copy.len() as u64;
+        next(c)
+    }
+}
+
+#[cfg(all(test, feature = "enc"))]
+mod tests {
+    use super::*;
+    use crate::{
+        diff, enc,
+        instructions::{apply_edits, EditParams},
+        DiffParams, Translator,
+    };
+    use std::io::Read;
+
+    fn filtered_patch<F: Filter>(
+        older: &[u8],
+        newer: &[u8],
+        params: &DiffParams,
+        mut filters: F,
+    ) -> Vec<u8> {
+        let mut w = enc::Writer::new(Vec::new()).unwrap();
+        let mut translator = Translator::new(older, newer, |c| {
+            filters.filter(c.into(), &mut |c| w.write(&c))
+        });
+        diff(older, newer, params, |m| translator.translate(m)).unwrap();
+        translator.close().unwrap();
+        filters.finish(&mut |c| w.write(&c)).unwrap();
+        w.into_inner()
+    }
+
+    #[test]
+    fn chained_filters() {
+        let older = b"the quick brown fox jumps over the lazy dog".repeat(200);
+        let instructions: Vec<u8> = (0..200_u32).map(|i| (i * 37) as u8).collect();
+        let newer = apply_edits(&older, &instructions, &EditParams::default());
+        let params = DiffParams::new(2, Some(256)).unwrap();
+
+        let (mut before, mut after) = (Stats::default(), Stats::default());
+        let patch = filtered_patch(
+            &older,
+            &newer,
+            &params,
+            (&mut before).then(Merge::default()).then(&mut after),
+        );
+
+        let mut fresh = Vec::new();
+        bipatch::Reader::new(&patch[..], io::Cursor::new(&older[..]))
+            .unwrap()
+            .read_to_end(&mut fresh)
+            .unwrap();
+        assert_eq!(fresh, newer);
+
+        assert!(after.controls < before.controls, "{:?}", after);
+        assert_eq!(before.add_bytes + before.copy_bytes, newer.len() as u64);
+        assert_eq!(after.add_bytes + after.copy_bytes, newer.len() as u64);
+
+        // merging controls gives the same result as merging matches
+        let mut expected = Vec::new();
+        crate::simple_diff_with_params(&older, &newer, &mut expected, &params.merge_controls(true))
+            .unwrap();
+        assert_eq!(patch, expected);
+    }
+}
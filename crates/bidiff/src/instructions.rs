@@ -1,7 +1,7 @@
 /// Generate a "newer" input from an "older" input and a set of instructions
 pub fn apply_instructions(older: &[u8], instructions: &[u8]) -> Vec<u8> {
     use std::cmp::min;
-    let mut newer: Vec<_> = older.iter().map(|x| *x).collect();
+    let mut newer: Vec<_> = older.to_vec();
 
     for couple in instructions.chunks(2) {
         if couple.len() != 2 {
@@ -12,10 +12,7 @@ pub fn apply_instructions(older: &[u8], instructions: &[u8]) -> Vec<u8> {
         if i < 128 {
             let pos = (i as usize) % newer.len();
             let len = j as usize;
-            let data: Vec<u8> = (&newer[pos..min(pos + len, newer.len())])
-                .iter()
-                .map(|x| *x)
-                .collect();
+            let data: Vec<u8> = newer[pos..min(pos + len, newer.len())].to_vec();
             for c in data {
                 newer.push(c);
             }
@@ -31,3 +28,207 @@ pub fn apply_instructions(older: &[u8], instructions: &[u8]) -> Vec<u8> {
     }
     newer
 }
+
+/// Relative weights of the edits performed by [apply_edits], and the
+/// maximum size of the blocks they operate on.
+///
+/// An edit with a weight of zero is never picked.
+#[derive(Debug, Clone)]
+pub struct EditParams {
+    /// Duplicate a block and insert it elsewhere
+    pub copy_weight: u32,
+    /// Insert a run of a single byte value (compresses well)
+    pub fill_weight: u32,
+    /// Swap two adjacent bytes
+    pub swap_weight: u32,
+    /// Remove a block
+    pub delete_weight: u32,
+    /// Remove a block and insert it elsewhere
+    pub move_weight: u32,
+    /// Flip bits in a single byte
+    pub flip_weight: u32,
+    /// Maximum length of copied, filled, deleted and moved blocks
+    pub max_block_len: usize,
+}
+
+impl Default for EditParams {
+    fn default() -> Self {
+        Self {
+            copy_weight: 4,
+            fill_weight: 2,
+            swap_weight: 2,
+            delete_weight: 3,
+            move_weight: 2,
+            flip_weight: 3,
+            max_block_len: 4096,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Edit {
+    Copy,
+    Fill,
+    Swap,
+    Delete,
+    Move,
+    Flip,
+}
+
+impl EditParams {
+    fn pick(&self, op: u8) -> Option<Edit> {
+        let weights = [
+            (self.copy_weight, Edit::Copy),
+            (self.fill_weight, Edit::Fill),
+            (self.swap_weight, Edit::Swap),
+            (self.delete_weight, Edit::Delete),
+            (self.move_weight, Edit::Move),
+            (self.flip_weight, Edit::Flip),
+        ];
+        let total: u32 = weights.iter().map(|(w, _)| w).sum();
+        if total == 0 {
+            return None;
+        }
+
+        let mut n = op as u32 % total;
+        for &(w, edit) in weights.iter() {
+            if n < w {
+                return Some(edit);
+            }
+            n -= w;
+        }
+        None
+    }
+}
+
+/// Generate a "newer" input from an "older" input by applying a series of
+/// edits, decoded from `instructions`.
+///
+/// Each edit consumes 5 bytes of `instructions`: the kind of edit (chosen
+/// according to the weights in `params`), a 16-bit position, a block length
+/// and an extra operand (a destination, or a byte value). Positions and
+/// lengths are scaled to the current size of the output, so the same
+/// instructions produce similar edits on small and large inputs.
+pub fn apply_edits(older: &[u8], instructions: &[u8], params: &EditParams) -> Vec<u8> {
+    let mut newer: Vec<_> = older.to_vec();
+
+    // maps `x` from [0, 2^bits) onto [0, len)
+    let scale = |x: usize, bits: u32, len: usize| ((x as u128 * len as u128) >> bits) as usize;
+
+    for quint in instructions.chunks_exact(5) {
+        let edit = match params.pick(quint[0]) {
+            Some(edit) => edit,
+            None => break,
+        };
+        let pos = scale(
+            u16::from_le_bytes([quint[1], quint[2]]) as usize,
+            16,
+            newer.len(),
+        );
+        let len = 1 + scale(quint[3] as usize, 8, params.max_block_len);
+        let end = std::cmp::min(pos + len, newer.len());
+        let extra = quint[4];
+
+        match edit {
+            Edit::Fill => {
+                newer.splice(pos..pos, vec![extra; len]);
+            }
+            _ if newer.is_empty() => {}
+            Edit::Copy => {
+                let block = newer[pos..end].to_vec();
+                let dest = scale(extra as usize, 8, newer.len() + 1);
+                newer.splice(dest..dest, block);
+            }
+            Edit::Swap => {
+                let other = (pos + 1) % newer.len();
+                newer.swap(pos, other);
+            }
+            Edit::Delete => {
+                newer.drain(pos..end);
+            }
+            Edit::Move => {
+                let block: Vec<u8> = newer.drain(pos..end).collect();
+                let dest = scale(extra as usize, 8, newer.len() + 1);
+                newer.splice(dest..dest, block);
+            }
+            Edit::Flip => {
+                newer[pos] ^= extra.max(1);
+            }
+        }
+    }
+    newer
+}
+
+/// Generate `len` bytes of structured data from `instructions`, as a
+/// stand-in for large real files: a mix of text, zeroes, noise, tables of
+/// counters and repeats of earlier data, rather than uniformly random
+/// bytes.
+///
+/// Each block consumes 4 bytes of `instructions`, which are cycled
+/// through as often as needed: the kind of block, a 16-bit length (blocks
+/// are up to 64 KiB) and a seed for its contents. Without a full block of
+/// instructions, the output is all zeroes.
+pub fn generate(instructions: &[u8], len: usize) -> Vec<u8> {
+    const WORDS: &[&[u8]] = &[
+        b"older", b"newer", b"patch", b"suffix", b"array", b"scan", b"the", b"of", b"\n",
+    ];
+
+    let mut out = Vec::with_capacity(len);
+    let blocks = instructions.chunks_exact(4).collect::<Vec<_>>();
+    if blocks.is_empty() {
+        out.resize(len, 0);
+        return out;
+    }
+
+    for (index, quad) in blocks.iter().cycle().enumerate() {
+        if out.len() >= len {
+            break;
+        }
+        let block_len = 1 + u16::from_le_bytes([quad[1], quad[2]]) as usize;
+        let block_len = std::cmp::min(block_len, len - out.len());
+        // the block index makes cycled instructions produce new contents
+        let mut state = (quad[3] as u64) << 32 | index as u64;
+        let mut next = move || {
+            // splitmix64
+            state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+            z ^ (z >> 31)
+        };
+
+        let end = out.len() + block_len;
+        match quad[0] % 5 {
+            0 => {
+                while out.len() < end {
+                    out.extend_from_slice(WORDS[next() as usize % WORDS.len()]);
+                    out.push(b' ');
+                }
+                out.truncate(end);
+            }
+            1 => out.resize(end, 0),
+            2 => {
+                while out.len() < end {
+                    out.extend_from_slice(&next().to_le_bytes());
+                }
+                out.truncate(end);
+            }
+            3 => {
+                let mut counter = next() as u32;
+                while out.len() < end {
+                    out.extend_from_slice(&counter.to_le_bytes());
+                    counter = counter.wrapping_add(1 + quad[3] as u32 % 16);
+                }
+                out.truncate(end);
+            }
+            _ if out.is_empty() => out.resize(end, 0),
+            _ => {
+                let start = next() as usize % out.len();
+                let n = std::cmp::min(block_len, out.len() - start);
+                out.extend_from_within(start..start + n);
+                out.resize(end, 0);
+            }
+        }
+    }
+    out
+}
@@ -6,15 +6,33 @@ use std::{
     cmp::min,
     error::Error,
     io::{self, Write},
-    time::Instant,
+    ops::Range,
+    time::{Duration, Instant},
 };
 
 #[cfg(feature = "enc")]
 pub mod enc;
 
+#[cfg(feature = "enc")]
+pub mod stream;
+
+#[cfg(feature = "enc")]
+pub mod endsley;
+
+#[cfg(feature = "enc")]
+pub mod segments;
+
 #[cfg(any(test, feature = "instructions"))]
 pub mod instructions;
 
+pub mod filter;
+
+mod policy;
+#[cfg(feature = "research")]
+pub use policy::{BsdiffPolicy, ScanPolicy};
+#[cfg(not(feature = "research"))]
+use policy::{BsdiffPolicy, ScanPolicy};
+
 #[derive(Debug)]
 pub struct Match {
     pub add_old_start: usize,
@@ -37,6 +55,47 @@ pub struct Control<'a> {
     pub seek: i64,
 }
 
+impl<'a> From<&Control<'a>> for Control<'a> {
+    fn from(c: &Control<'a>) -> Self {
+        c.clone()
+    }
+}
+
+/// An owned [Control], which can be stored, or sent to another thread (to
+/// encode controls there, for example). Writers accept both.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ControlBuf {
+    pub add: Vec<u8>,
+    pub copy: Vec<u8>,
+    pub seek: i64,
+}
+
+impl ControlBuf {
+    pub fn as_control(&self) -> Control<'_> {
+        Control {
+            add: &self.add,
+            copy: &self.copy,
+            seek: self.seek,
+        }
+    }
+}
+
+impl From<&Control<'_>> for ControlBuf {
+    fn from(c: &Control<'_>) -> Self {
+        Self {
+            add: c.add.to_vec(),
+            copy: c.copy.to_vec(),
+            seek: c.seek,
+        }
+    }
+}
+
+impl<'a> From<&'a ControlBuf> for Control<'a> {
+    fn from(c: &'a ControlBuf) -> Self {
+        c.as_control()
+    }
+}
+
 pub struct Translator<'a, F, E>
 where
     F: FnMut(&Control) -> Result<(), E>,
@@ -48,6 +107,8 @@ where
     buf: Vec<u8>,
     on_control: F,
     closed: bool,
+    min_copy_len: usize,
+    merge_controls: bool,
 }
 
 impl<'a, F, E> Translator<'a, F, E>
@@ -63,9 +124,31 @@ where
             prev_match: None,
             on_control,
             closed: false,
+            min_copy_len: 0,
+            merge_controls: false,
         }
     }
 
+    /// Fold copies shorter than `len` bytes into the preceding add, when the
+    /// older file is long enough. The folded bytes are then encoded as a
+    /// difference against the older file rather than as literals, which
+    /// some compressors handle better. 0 (the default) disables folding.
+    pub fn min_copy_len(mut self, len: usize) -> Self {
+        self.min_copy_len = len;
+        self
+    }
+
+    /// Merge matches into the previous one instead of emitting a control
+    /// for each, when that doesn't change the output: matches without an
+    /// add (including empty ones, which only seek) are appended to the
+    /// previous copy, and matches whose add continues where the previous
+    /// add left off, with no copy in between, extend that add. Disabled by
+    /// default.
+    pub fn merge_controls(mut self, enabled: bool) -> Self {
+        self.merge_controls = enabled;
+        self
+    }
+
     fn send_control(&mut self, m: Option<&Match>) -> Result<(), E> {
         if let Some(pm) = self.prev_match.take() {
             (self.on_control)(&Control {
@@ -82,6 +165,25 @@ where
     }
 
     pub fn translate(&mut self, m: Match) -> Result<(), E> {
+        let m = fold_short_copy(m, self.min_copy_len, self.obuf.len());
+
+        if self.merge_controls {
+            if let Some(pm) = self.prev_match.as_mut() {
+                let extends_add = pm.copy_end == pm.copy_start()
+                    && m.add_old_start == pm.add_old_start + pm.add_length;
+                if m.add_length == 0 || extends_add {
+                    let nbuf = &self.nbuf;
+                    let obuf = &self.obuf;
+                    self.buf.extend((0..m.add_length).map(|i| {
+                        nbuf[m.add_new_start + i].wrapping_sub(obuf[m.add_old_start + i])
+                    }));
+                    pm.add_length += m.add_length;
+                    pm.copy_end = m.copy_end;
+                    return Ok(());
+                }
+            }
+        }
+
         self.send_control(Some(&m))?;
 
         self.buf.clear();
@@ -127,21 +229,24 @@ where
     }
 }
 
-struct BsdiffIterator<'a> {
+struct BsdiffIterator<'a, P: ScanPolicy = BsdiffPolicy> {
     scan: usize,
     pos: usize,
     length: usize,
     lastscan: usize,
     lastpos: usize,
     lastoffset: isize,
+    skip_dissimilar: bool,
+    deadline: Option<Instant>,
 
     obuf: &'a [u8],
     nbuf: &'a [u8],
     sa: &'a dyn StringIndex<'a>,
+    policy: P,
 }
 
-impl<'a> BsdiffIterator<'a> {
-    pub fn new(obuf: &'a [u8], nbuf: &'a [u8], sa: &'a dyn StringIndex<'a>) -> Self {
+impl<'a, P: ScanPolicy> BsdiffIterator<'a, P> {
+    pub fn new(obuf: &'a [u8], nbuf: &'a [u8], sa: &'a dyn StringIndex<'a>, policy: P) -> Self {
         Self {
             scan: 0,
             pos: 0,
@@ -149,14 +254,49 @@ impl<'a> BsdiffIterator<'a> {
             lastscan: 0,
             lastpos: 0,
             lastoffset: 0,
+            skip_dissimilar: false,
+            deadline: None,
             obuf,
             nbuf,
             sa,
+            policy,
         }
     }
+
+    /// Start scanning as if the beginning of `nbuf` lined up with
+    /// `old_start` in `obuf`, rather than with its beginning.
+    pub fn starting_at(mut self, old_start: usize) -> Self {
+        self.lastpos = old_start;
+        self.lastoffset = old_start as isize;
+        self
+    }
+
+    /// Advance faster through long stretches without useful matches,
+    /// see [DiffParams::skip_dissimilar].
+    pub fn skip_dissimilar(mut self, enabled: bool) -> Self {
+        self.skip_dissimilar = enabled;
+        self
+    }
+
+    /// Stop looking for matches once `deadline` has passed: the rest of
+    /// `nbuf` ends up in the copy part of the last match.
+    pub fn deadline(mut self, deadline: Option<Instant>) -> Self {
+        self.deadline = deadline;
+        self
+    }
 }
 
-impl<'a> Iterator for BsdiffIterator<'a> {
+/// Number of consecutive positions without a useful match after which
+/// the scanner starts skipping, and doubles its step
+const SKIP_THRESHOLD: usize = 64;
+
+/// Largest step taken when skipping through dissimilar data
+const MAX_SKIP: usize = 64;
+
+/// Number of positions scanned between deadline checks
+const DEADLINE_CHECK_INTERVAL: usize = 4096;
+
+impl<'a, P: ScanPolicy> Iterator for BsdiffIterator<'a, P> {
     type Item = Match;
     fn next(&mut self) -> Option<Self::Item> {
         let obuflen = self.obuf.len();
@@ -164,10 +304,24 @@ impl<'a> Iterator for BsdiffIterator<'a> {
 
         while self.scan < nbuflen {
             let mut oldscore = 0_usize;
+            let mut misses = 0_usize;
             self.scan += self.length;
 
             let mut scsc = self.scan;
+            let mut until_deadline_check = 0;
             'inner: while self.scan < nbuflen {
+                if let Some(deadline) = self.deadline {
+                    if until_deadline_check == 0 {
+                        if Instant::now() >= deadline {
+                            // give up: everything from here on gets copied
+                            self.scan = nbuflen;
+                            break 'inner;
+                        }
+                        until_deadline_check = DEADLINE_CHECK_INTERVAL;
+                    }
+                    until_deadline_check -= 1;
+                }
+
                 let res = self.sa.longest_substring_match(&self.nbuf[self.scan..]);
                 self.pos = res.start;
                 self.length = res.len;
@@ -182,21 +336,30 @@ impl<'a> Iterator for BsdiffIterator<'a> {
                     }
                 }
 
-                let significantly_better = self.length > oldscore + 8;
-                let same_length = self.length == oldscore && self.length != 0;
-
-                if same_length || significantly_better {
+                if self.policy.is_better(self.length, oldscore) {
                     break 'inner;
                 }
 
-                {
-                    let oi = (self.scan as isize + self.lastoffset) as usize;
-                    if oi < obuflen && self.obuf[oi] == self.nbuf[self.scan] {
+                // without skipping, step is always 1
+                let step = if self.skip_dissimilar {
+                    misses += 1;
+                    let doublings = min(misses / SKIP_THRESHOLD, 6) as u32;
+                    min(1 << doublings, MAX_SKIP)
+                } else {
+                    1
+                };
+                let step = min(step, nbuflen - self.scan);
+
+                // positions we step over no longer count towards oldscore
+                for i in self.scan..min(self.scan + step, scsc) {
+                    let oi = (i as isize + self.lastoffset) as usize;
+                    if oi < obuflen && self.obuf[oi] == self.nbuf[i] {
                         oldscore -= 1;
                     }
                 }
 
-                self.scan += 1;
+                self.scan += step;
+                scsc = scsc.max(self.scan);
             } // 'inner
 
             let done_scanning = self.scan == nbuflen;
@@ -214,7 +377,8 @@ impl<'a> Iterator for BsdiffIterator<'a> {
                             // the original code has an `i++` in the
                             // middle of what's essentially a while loop.
                             let i = i + 1;
-                            if s * 2 - i as isize > sf * 2 - lenf {
+                            let p = &self.policy;
+                            if p.extension_score(s, i as isize) > p.extension_score(sf, lenf) {
                                 sf = s;
                                 lenf = i as isize;
                             }
@@ -234,7 +398,8 @@ impl<'a> Iterator for BsdiffIterator<'a> {
                             s += 1;
                         }
 
-                        if (s * 2 - i as isize) > (sb * 2 - lenb) {
+                        let p = &self.policy;
+                        if p.extension_score(s, i as isize) > p.extension_score(sb, lenb) {
                             sb = s;
                             lenb = i as isize;
                         }
@@ -297,10 +462,38 @@ impl<'a> Iterator for BsdiffIterator<'a> {
     }
 }
 
+/// A known correspondence between a position in the older file and a
+/// position in the newer file, for example the start of the same section
+/// in two builds of an executable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Anchor {
+    pub old_offset: usize,
+    pub new_offset: usize,
+}
+
 /// Parameters used when creating diffs
+///
+/// With the `serde` feature, they can be serialized, and loaded from
+/// configuration files: every field is optional and defaults to what
+/// [DiffParams::default] uses, and invalid values are rejected like
+/// [DiffParams::new] does. A custom scan policy isn't serialized.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "DiffParamsDef"))]
 pub struct DiffParams {
     sort_partitions: usize,
     scan_chunk_size: Option<usize>,
+    fast_identical_regions: bool,
+    skip_dissimilar: bool,
+    min_copy_len: usize,
+    merge_controls: bool,
+    deadline: Option<Duration>,
+    anchors: Vec<Anchor>,
+    max_backref_distance: Option<usize>,
+    #[cfg(feature = "research")]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    scan_policy: Option<std::sync::Arc<dyn ScanPolicy>>,
 }
 
 impl DiffParams {
@@ -328,8 +521,112 @@ impl DiffParams {
         Ok(Self {
             sort_partitions,
             scan_chunk_size,
+            ..Default::default()
         })
     }
+
+    /// Compare the older and newer file block by block, at the same offsets,
+    /// before scanning. Runs of identical blocks are matched directly and are
+    /// not scanned at all, which speeds up diffing inputs where only a few
+    /// sections changed (like firmware images). Disabled by default.
+    pub fn fast_identical_regions(mut self, enabled: bool) -> Self {
+        self.fast_identical_regions = enabled;
+        self
+    }
+
+    /// Step through long stretches of the newer file that have no useful
+    /// match in the older file with exponentially increasing strides,
+    /// instead of one byte at a time. This speeds up diffing mostly
+    /// dissimilar inputs considerably, at the cost of finding matches a
+    /// little later than they start (the backwards extension of each match
+    /// recovers most of that). Disabled by default.
+    pub fn skip_dissimilar(mut self, enabled: bool) -> Self {
+        self.skip_dissimilar = enabled;
+        self
+    }
+
+    /// Stop scanning once `deadline` has passed since diffing started, and
+    /// treat everything not scanned yet as new data. The patch is still
+    /// valid, only larger. Suffix sorting can't be interrupted, so it may
+    /// still take longer than `deadline` for large older files.
+    pub fn deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Fold copies shorter than `len` bytes into adds when writing patches
+    /// with the `simple_diff*` functions, see [Translator::min_copy_len].
+    ///
+    /// Disabled (0) by default: with deflate, folding copies of 8 bytes or
+    /// less barely changes patch sizes, and folding longer ones makes them
+    /// larger (by about 5% at 32 bytes). Codecs that handle short literals
+    /// poorly may still benefit.
+    pub fn min_copy_len(mut self, len: usize) -> Self {
+        self.min_copy_len = len;
+        self
+    }
+
+    /// Merge consecutive matches into a single control when writing patches
+    /// with the `simple_diff*` functions, see [Translator::merge_controls].
+    ///
+    /// Mostly useful with a `scan_chunk_size`, since matches are split at
+    /// chunk boundaries: on text-like data scanned in 32 KiB chunks, this
+    /// brings deflated patches back down to the size of unchunked ones
+    /// (about 15% smaller). Disabled by default.
+    pub fn merge_controls(mut self, enabled: bool) -> Self {
+        self.merge_controls = enabled;
+        self
+    }
+
+    /// Replace the heuristics used by the scanner (requires the `research`
+    /// feature). Patches stay valid with any policy, only their size and
+    /// the time it takes to produce them change.
+    #[cfg(feature = "research")]
+    pub fn scan_policy<P: ScanPolicy + 'static>(mut self, policy: P) -> Self {
+        self.scan_policy = Some(std::sync::Arc::new(policy));
+        self
+    }
+
+    /// Use known correspondences between the older and newer file to guide
+    /// scanning. The newer file is scanned in regions that start at each
+    /// anchor, and each region initially assumes the data at the anchor's
+    /// new offset lines up with its old offset, which improves matches for
+    /// relocated data. Anchors whose old offset is past the end of the
+    /// older file are ignored.
+    pub fn anchors(mut self, mut anchors: Vec<Anchor>) -> Self {
+        anchors.sort_by_key(|a| a.new_offset);
+        self.anchors = anchors;
+        self
+    }
+
+    /// Only use matches that start within `distance` bytes (before or after)
+    /// of the furthest position read in the older file so far, and store
+    /// the rest as new data. Appliers then only need to keep the last
+    /// `distance` bytes of the older file they read, so they can stream it
+    /// (from the network, say) through a sliding window. Patches written
+    /// by [simple_diff_with_params] record `distance` in their header.
+    ///
+    /// The limit applies to each call to [diff]: windowed diffs in the
+    /// `stream` module only respect it within each window. No limit by
+    /// default.
+    pub fn max_backref_distance(mut self, distance: usize) -> Self {
+        self.max_backref_distance = Some(distance);
+        self
+    }
+
+    /// Anchors that point into an older file of `old_len` bytes
+    fn anchors_within(&self, old_len: usize) -> impl DoubleEndedIterator<Item = &Anchor> {
+        self.anchors.iter().filter(move |a| a.old_offset < old_len)
+    }
+
+    /// Position in the older file that `new_offset` lines up with,
+    /// according to the closest anchor before it.
+    fn old_start_for(&self, new_offset: usize, old_len: usize) -> Option<usize> {
+        self.anchors_within(old_len)
+            .rev()
+            .find(|a| a.new_offset <= new_offset)
+            .map(|a| min(a.old_offset + (new_offset - a.new_offset), old_len))
+    }
 }
 
 impl Default for DiffParams {
@@ -337,63 +634,253 @@ impl Default for DiffParams {
         Self {
             sort_partitions: 1,
             scan_chunk_size: None,
+            fast_identical_regions: false,
+            skip_dissimilar: false,
+            min_copy_len: 0,
+            merge_controls: false,
+            deadline: None,
+            anchors: Vec::new(),
+            max_backref_distance: None,
+            #[cfg(feature = "research")]
+            scan_policy: None,
         }
     }
 }
 
-/// Diff two files
-pub fn diff<F, E>(obuf: &[u8], nbuf: &[u8], params: &DiffParams, mut on_match: F) -> Result<(), E>
-where
-    F: FnMut(Match) -> Result<(), E>,
-{
-    info!("building suffix array...");
-    let before_suffix = Instant::now();
-    let sa = PartitionedSuffixArray::new(obuf, params.sort_partitions, divsufsort::sort);
-    info!(
-        "sorting took {}",
-        DurationSpeed(obuf.len() as u64, before_suffix.elapsed())
-    );
+impl fmt::Debug for DiffParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = f.debug_struct("DiffParams");
+        s.field("sort_partitions", &self.sort_partitions)
+            .field("scan_chunk_size", &self.scan_chunk_size)
+            .field("fast_identical_regions", &self.fast_identical_regions)
+            .field("skip_dissimilar", &self.skip_dissimilar)
+            .field("min_copy_len", &self.min_copy_len)
+            .field("merge_controls", &self.merge_controls)
+            .field("deadline", &self.deadline)
+            .field("anchors", &self.anchors)
+            .field("max_backref_distance", &self.max_backref_distance);
+        #[cfg(feature = "research")]
+        s.field(
+            "scan_policy",
+            &self.scan_policy.as_ref().map(|_| "<custom>"),
+        );
+        s.finish()
+    }
+}
 
-    let before_scan = Instant::now();
-    if let Some(chunk_size) = params.scan_chunk_size {
-        // +1 to make sure we don't have > num_partitions
-        let num_chunks = (nbuf.len() + chunk_size - 1) / chunk_size;
+/// What [DiffParams] are deserialized from, before they're checked
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct DiffParamsDef {
+    sort_partitions: usize,
+    scan_chunk_size: Option<usize>,
+    fast_identical_regions: bool,
+    skip_dissimilar: bool,
+    min_copy_len: usize,
+    merge_controls: bool,
+    deadline: Option<Duration>,
+    anchors: Vec<Anchor>,
+    max_backref_distance: Option<usize>,
+}
+
+#[cfg(feature = "serde")]
+impl Default for DiffParamsDef {
+    fn default() -> Self {
+        let p = DiffParams::default();
+        Self {
+            sort_partitions: p.sort_partitions,
+            scan_chunk_size: p.scan_chunk_size,
+            fast_identical_regions: p.fast_identical_regions,
+            skip_dissimilar: p.skip_dissimilar,
+            min_copy_len: p.min_copy_len,
+            merge_controls: p.merge_controls,
+            deadline: p.deadline,
+            anchors: p.anchors,
+            max_backref_distance: p.max_backref_distance,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl std::convert::TryFrom<DiffParamsDef> for DiffParams {
+    type Error = Box<dyn Error + Send + Sync + 'static>;
+
+    fn try_from(d: DiffParamsDef) -> Result<Self, Self::Error> {
+        let mut params = DiffParams::new(d.sort_partitions, d.scan_chunk_size)?
+            .fast_identical_regions(d.fast_identical_regions)
+            .skip_dissimilar(d.skip_dissimilar)
+            .min_copy_len(d.min_copy_len)
+            .merge_controls(d.merge_controls)
+            .anchors(d.anchors);
+        params.deadline = d.deadline;
+        params.max_backref_distance = d.max_backref_distance;
+        Ok(params)
+    }
+}
+
+/// A suffix array over an older file.
+///
+/// Building it is the most expensive part of diffing, so when diffing the
+/// same older file against several newer files, build it once and pass it
+/// to [diff_with_index]. It can be shared between threads, to run several
+/// scans concurrently.
+pub struct OldIndex<'a> {
+    obuf: &'a [u8],
+    /// `None` when the older file is empty, or when it's known that no
+    /// scanning is needed
+    sa: Option<PartitionedSuffixArray<'a, i32>>,
+}
+
+impl<'a> OldIndex<'a> {
+    /// Sort `obuf`, using `params.sort_partitions` partitions. Other
+    /// parameters only matter when diffing.
+    pub fn new(obuf: &'a [u8], params: &DiffParams) -> Self {
+        if obuf.is_empty() {
+            return Self { obuf, sa: None };
+        }
 
+        info!("building suffix array...");
+        let before_suffix = Instant::now();
+        let sa = PartitionedSuffixArray::new(obuf, params.sort_partitions, divsufsort::sort);
         info!(
-            "scanning with {}B chunks... ({} chunks total)",
-            chunk_size, num_chunks
+            "sorting took {}",
+            DurationSpeed(obuf.len() as u64, before_suffix.elapsed())
         );
 
-        let mut txs = Vec::with_capacity(num_chunks);
-        let mut rxs = Vec::with_capacity(num_chunks);
-        for _ in 0..num_chunks {
-            let (tx, rx) = std::sync::mpsc::channel::<Vec<Match>>();
-            txs.push(tx);
-            rxs.push(rx);
-        }
+        Self { obuf, sa: Some(sa) }
+    }
+
+    /// The older file this index was built for
+    pub fn older(&self) -> &'a [u8] {
+        self.obuf
+    }
+}
 
-        nbuf.par_chunks(chunk_size).zip(txs).for_each(|(nbuf, tx)| {
-            let iter = BsdiffIterator::new(obuf, nbuf, &sa);
-            tx.send(iter.collect()).expect("should send results");
+/// Diff two files
+///
+/// Degenerate inputs skip suffix sorting and scanning entirely:
+///
+/// - if `nbuf` is empty, no matches are produced
+/// - if `obuf` is empty, a single match copies all of `nbuf`
+/// - if both are identical, a single match adds all of `obuf`
+///   (with an all-zero difference)
+pub fn diff<F, E>(obuf: &[u8], nbuf: &[u8], params: &DiffParams, on_match: F) -> Result<(), E>
+where
+    F: FnMut(Match) -> Result<(), E>,
+{
+    let started = Instant::now();
+    let index = if nbuf.is_empty() || obuf == nbuf {
+        OldIndex { obuf, sa: None }
+    } else {
+        OldIndex::new(obuf, params)
+    };
+    diff_inner(&index, nbuf, params, started, on_match)
+}
+
+/// Diff the older file `index` was built for against `nbuf`, like [diff].
+///
+/// `params.sort_partitions` is ignored, since sorting already happened,
+/// and a deadline only counts time spent scanning.
+pub fn diff_with_index<F, E>(
+    index: &OldIndex,
+    nbuf: &[u8],
+    params: &DiffParams,
+    on_match: F,
+) -> Result<(), E>
+where
+    F: FnMut(Match) -> Result<(), E>,
+{
+    diff_inner(index, nbuf, params, Instant::now(), on_match)
+}
+
+/// Diff with a deadline counted from `started`, if there is one
+fn diff_inner<F, E>(
+    index: &OldIndex,
+    nbuf: &[u8],
+    params: &DiffParams,
+    started: Instant,
+    mut on_match: F,
+) -> Result<(), E>
+where
+    F: FnMut(Match) -> Result<(), E>,
+{
+    let obuf = index.obuf;
+    if nbuf.is_empty() {
+        return Ok(());
+    }
+    if obuf.is_empty() || obuf == nbuf {
+        info!("degenerate input, skipping scan");
+        return on_match(Match {
+            add_old_start: 0,
+            add_new_start: 0,
+            add_length: obuf.len(),
+            copy_end: nbuf.len(),
         });
+    }
 
-        for (i, rx) in rxs.into_iter().enumerate() {
-            let offset = i * chunk_size;
-            let v = rx.recv().expect("should receive results");
-            for mut m in v {
-                // if m.add_length == 0 && m.copy_end == m.copy_start() {
-                //     continue;
-                // }
+    // patches start reading the older file at its beginning, so if the first
+    // match starts elsewhere (because of anchors), seek there with an empty
+    // match first.
+    let mut first = true;
+    let mut window = params
+        .max_backref_distance
+        .map(|distance| BackrefWindow::new(distance, params.min_copy_len, obuf.len()));
+    let mut on_match = |m: Match| {
+        let m = match window.as_mut() {
+            Some(window) => window.constrain(m),
+            None => m,
+        };
+        if first && m.add_old_start != 0 {
+            on_match(Match {
+                add_old_start: 0,
+                add_new_start: 0,
+                add_length: 0,
+                copy_end: 0,
+            })?;
+        }
+        first = false;
+        on_match(m)
+    };
+
+    let sa = index
+        .sa
+        .as_ref()
+        .expect("non-degenerate inputs should have a suffix array");
+
+    let scanner = Scanner {
+        obuf,
+        sa,
+        params,
+        deadline: params.deadline.map(|d| started + d),
+    };
 
-                m.add_new_start += offset;
-                m.copy_end += offset;
-                on_match(m)?;
+    let before_scan = Instant::now();
+    if params.fast_identical_regions {
+        let mut pos = 0;
+        // each gap after an identical region lines up with its end
+        let mut old_start = None;
+        let mut skipped = 0;
+        for region in identical_regions(obuf, nbuf, IDENTICAL_BLOCK_SIZE) {
+            if region.start > pos {
+                scanner.scan(&nbuf[pos..region.start], pos, old_start, &mut on_match)?;
             }
+            skipped += region.len();
+            on_match(Match {
+                add_old_start: region.start,
+                add_new_start: region.start,
+                add_length: region.len(),
+                copy_end: region.end,
+            })?;
+            pos = region.end;
+            old_start = Some(region.end);
         }
-    } else {
-        for m in BsdiffIterator::new(obuf, nbuf, &sa) {
-            on_match(m)?
+        if pos < nbuf.len() {
+            scanner.scan(&nbuf[pos..], pos, old_start, &mut on_match)?;
         }
+        info!("skipped {} of identical regions", Size(skipped as u64));
+    } else {
+        scanner.scan(nbuf, 0, None, &mut on_match)?;
     }
 
     info!(
@@ -404,6 +891,233 @@ where
     Ok(())
 }
 
+/// Fold `m`'s copy into its add, as [Translator::min_copy_len] describes
+fn fold_short_copy(mut m: Match, min_copy_len: usize, old_len: usize) -> Match {
+    let copy_len = m.copy_end - m.copy_start();
+    if copy_len > 0
+        && copy_len < min_copy_len
+        && m.add_old_start + m.add_length + copy_len <= old_len
+    {
+        m.add_length += copy_len;
+    }
+    m
+}
+
+/// Keeps matches within [DiffParams::max_backref_distance] of the
+/// furthest position read in the older file
+struct BackrefWindow {
+    distance: usize,
+    /// short copies are folded the way translators will fold them, so
+    /// that the bytes they read count too
+    min_copy_len: usize,
+    old_len: usize,
+    /// where the last add stopped reading the older file
+    pos: usize,
+    /// the furthest position read in the older file
+    high: usize,
+}
+
+impl BackrefWindow {
+    fn new(distance: usize, min_copy_len: usize, old_len: usize) -> Self {
+        Self {
+            distance,
+            min_copy_len,
+            old_len,
+            pos: 0,
+            high: 0,
+        }
+    }
+
+    /// Turn `m` into new data if its add starts too far away. Short copies
+    /// are folded first, and the matches returned are left with nothing a
+    /// translator would fold again.
+    fn constrain(&mut self, m: Match) -> Match {
+        let mut m = fold_short_copy(m, self.min_copy_len, self.old_len);
+        if m.add_length == 0 {
+            return m;
+        }
+
+        let start = m.add_old_start;
+        if start.saturating_add(self.distance) < self.high
+            || start > self.high.saturating_add(self.distance)
+        {
+            // `pos` is within the window, so reading on from there is fine
+            let new_data = Match {
+                add_old_start: self.pos,
+                add_new_start: m.add_new_start,
+                add_length: 0,
+                copy_end: m.copy_end,
+            };
+            m = fold_short_copy(new_data, self.min_copy_len, self.old_len);
+            if m.add_length == 0 {
+                return m;
+            }
+        }
+
+        self.pos = m.add_old_start + m.add_length;
+        self.high = std::cmp::max(self.high, self.pos);
+        m
+    }
+}
+
+/// What's needed to scan regions of a newer file
+struct Scanner<'a> {
+    obuf: &'a [u8],
+    sa: &'a PartitionedSuffixArray<'a, i32>,
+    params: &'a DiffParams,
+    deadline: Option<Instant>,
+}
+
+impl<'a> Scanner<'a> {
+    /// Scan `nbuf`, which starts at `offset` in the newer file, reporting
+    /// matches with positions relative to the whole newer file. Its start
+    /// lines up with `old_start` in the older file if given, or according
+    /// to the anchors otherwise.
+    fn scan<F, E>(
+        &self,
+        nbuf: &'a [u8],
+        offset: usize,
+        old_start: Option<usize>,
+        on_match: &mut F,
+    ) -> Result<(), E>
+    where
+        F: FnMut(Match) -> Result<(), E>,
+    {
+        let params = self.params;
+        let end = offset + nbuf.len();
+        let splits = params
+            .anchors_within(self.obuf.len())
+            .map(|a| a.new_offset)
+            .filter(|&n| n > offset && n < end);
+
+        let mut start = offset;
+        for split in splits.chain(std::iter::once(end)) {
+            if split > start {
+                let old_start = match old_start {
+                    Some(old_start) if start == offset => Some(old_start),
+                    _ => params.old_start_for(start, self.obuf.len()),
+                };
+                let nbuf = &nbuf[start - offset..split - offset];
+                #[cfg(feature = "research")]
+                {
+                    if let Some(policy) = params.scan_policy.as_deref() {
+                        self.scan_region(nbuf, start, old_start, policy, on_match)?;
+                        start = split;
+                        continue;
+                    }
+                }
+                self.scan_region(nbuf, start, old_start, BsdiffPolicy, on_match)?;
+                start = split;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Scan a region of the newer file that starts at `offset`, optionally
+    /// lined up with `old_start` in the older file.
+    fn scan_region<P, F, E>(
+        &self,
+        nbuf: &'a [u8],
+        offset: usize,
+        old_start: Option<usize>,
+        policy: P,
+        on_match: &mut F,
+    ) -> Result<(), E>
+    where
+        P: ScanPolicy + Copy,
+        F: FnMut(Match) -> Result<(), E>,
+    {
+        let (obuf, sa, params) = (self.obuf, self.sa, self.params);
+
+        // `delta` is the position of `nbuf` within the region
+        let iter = |delta: usize, nbuf: &'a [u8]| {
+            let iter = BsdiffIterator::new(obuf, nbuf, sa, policy)
+                .skip_dissimilar(params.skip_dissimilar)
+                .deadline(self.deadline);
+            match old_start {
+                Some(s) => iter.starting_at(min(s + delta, obuf.len())),
+                None => iter,
+            }
+        };
+
+        if let Some(chunk_size) = params.scan_chunk_size {
+            // +1 to make sure we don't have > num_partitions
+            let num_chunks = nbuf.len().div_ceil(chunk_size);
+
+            info!(
+                "scanning with {}B chunks... ({} chunks total)",
+                chunk_size, num_chunks
+            );
+
+            let mut txs = Vec::with_capacity(num_chunks);
+            let mut rxs = Vec::with_capacity(num_chunks);
+            for _ in 0..num_chunks {
+                let (tx, rx) = std::sync::mpsc::channel::<Vec<Match>>();
+                txs.push(tx);
+                rxs.push(rx);
+            }
+
+            nbuf.par_chunks(chunk_size)
+                .enumerate()
+                .zip(txs)
+                .for_each(|((i, nbuf), tx)| {
+                    let iter = iter(i * chunk_size, nbuf);
+                    tx.send(iter.collect()).expect("should send results");
+                });
+
+            for (i, rx) in rxs.into_iter().enumerate() {
+                let offset = offset + i * chunk_size;
+                let v = rx.recv().expect("should receive results");
+                for mut m in v {
+                    // if m.add_length == 0 && m.copy_end == m.copy_start() {
+                    //     continue;
+                    // }
+
+                    m.add_new_start += offset;
+                    m.copy_end += offset;
+                    on_match(m)?;
+                }
+            }
+        } else {
+            for mut m in iter(0, nbuf) {
+                m.add_new_start += offset;
+                m.copy_end += offset;
+                on_match(m)?
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Block size used to look for identical regions when
+/// `DiffParams::fast_identical_regions` is enabled.
+const IDENTICAL_BLOCK_SIZE: usize = 64 * 1024;
+
+/// Find runs of aligned blocks that are identical in `obuf` and `nbuf`
+/// at the same offsets.
+fn identical_regions(obuf: &[u8], nbuf: &[u8], block_size: usize) -> Vec<Range<usize>> {
+    let mut regions: Vec<Range<usize>> = Vec::new();
+    let len = min(obuf.len(), nbuf.len());
+
+    for (i, (o, n)) in obuf[..len]
+        .chunks_exact(block_size)
+        .zip(nbuf[..len].chunks_exact(block_size))
+        .enumerate()
+    {
+        if o != n {
+            continue;
+        }
+        let block = i * block_size..(i + 1) * block_size;
+        match regions.last_mut() {
+            Some(last) if last.end == block.start => last.end = block.end,
+            _ => regions.push(block),
+        }
+    }
+    regions
+}
+
 use std::fmt;
 
 struct DurationSpeed(u64, std::time::Duration);
@@ -453,16 +1167,205 @@ pub fn simple_diff_with_params(
     out: &mut dyn Write,
     diff_params: &DiffParams,
 ) -> Result<(), io::Error> {
-    let mut w = enc::Writer::new(out)?;
+    let mut w = enc::Writer::with_max_backref_distance(out, diff_params.max_backref_distance)?;
 
-    let mut translator = Translator::new(older, newer, |control| w.write(control));
+    let mut translator = Translator::new(older, newer, |control| w.write(control))
+        .min_copy_len(diff_params.min_copy_len)
+        .merge_controls(diff_params.merge_controls);
     diff(older, newer, diff_params, |m| translator.translate(m))?;
     translator.close()?;
 
     Ok(())
 }
 
+/// Diff the files at `older` and `newer`, and write the patch to a new file
+/// at `out`.
+///
+/// Both inputs are read into memory in full (the diff needs random access
+/// to both, and a suffix array over the older file takes several times its
+/// size anyway), so this keeps the crate free of unsafe memory mapping.
+#[cfg(feature = "enc")]
+pub fn simple_diff_files<P, Q, R>(
+    older: P,
+    newer: Q,
+    out: R,
+    diff_params: &DiffParams,
+) -> Result<(), io::Error>
+where
+    P: AsRef<std::path::Path>,
+    Q: AsRef<std::path::Path>,
+    R: AsRef<std::path::Path>,
+{
+    let older = std::fs::read(older)?;
+    let newer = std::fs::read(newer)?;
+
+    let mut out = io::BufWriter::new(std::fs::File::create(out)?);
+    simple_diff_with_params(&older, &newer, &mut out, diff_params)?;
+    out.flush()
+}
+
+/// When to give up on diffing dissimilar inputs, see
+/// [simple_diff_with_bailout].
+#[derive(Debug, Clone, Copy)]
+pub struct Bailout {
+    /// Fraction of the newer file (between 0 and 1) to scan before
+    /// checking coverage
+    pub scanned: f64,
+    /// Minimum fraction of the scanned part of the newer file that must be
+    /// matched against the older file to keep going
+    pub min_coverage: f64,
+}
+
+/// What kind of patch [simple_diff_with_bailout] wrote
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchKind {
+    /// A regular diff
+    Diff,
+    /// The inputs were too dissimilar, so the newer file was stored as-is
+    Stored,
+}
+
+/// Like [simple_diff_with_params], but stops scanning when the inputs turn
+/// out to be too dissimilar for a diff to be worth it, and writes a patch
+/// that stores the newer file instead (which compresses about as well as
+/// the newer file itself).
+///
+/// Matches are buffered until scanning is done. With a `scan_chunk_size`,
+/// chunks are scanned in parallel before coverage can be checked, so
+/// bailing out saves less time.
+#[cfg(feature = "enc")]
+pub fn simple_diff_with_bailout(
+    older: &[u8],
+    newer: &[u8],
+    out: &mut dyn Write,
+    diff_params: &DiffParams,
+    bailout: &Bailout,
+) -> Result<PatchKind, io::Error> {
+    struct Bailed;
+
+    let check_at = (newer.len() as f64 * bailout.scanned) as usize;
+    let mut matched = 0;
+    let mut matches = Vec::new();
+    let res = diff(older, newer, diff_params, |m| {
+        matched += m.add_length;
+        if m.copy_end >= check_at
+            && m.copy_end > 0
+            && (matched as f64) < m.copy_end as f64 * bailout.min_coverage
+        {
+            return Err(Bailed);
+        }
+        matches.push(m);
+        Ok(())
+    });
+
+    match res {
+        Ok(()) => {
+            write_matches(older, newer, matches, out, diff_params)?;
+            Ok(PatchKind::Diff)
+        }
+        Err(Bailed) => {
+            info!(
+                "only {} of {} matched, storing",
+                Size(matched as u64),
+                Size(newer.len() as u64)
+            );
+            let store = Match {
+                add_old_start: 0,
+                add_new_start: 0,
+                add_length: 0,
+                copy_end: newer.len(),
+            };
+            write_matches(older, newer, Some(store), out, &Default::default())?;
+            Ok(PatchKind::Stored)
+        }
+    }
+}
+
+/// Write a patch from an externally produced series of matches (from a
+/// custom matcher, or replayed from logs), translated and encoded the same
+/// way as [simple_diff] does.
+///
+/// Matches must cover all of `newer`, in order: the first one starts at 0,
+/// each one starts where the previous one's copy ends, and the last one
+/// ends at `newer.len()`. Matches that don't, or that are out of bounds,
+/// are reported as `InvalidInput` errors.
+#[cfg(feature = "enc")]
+pub fn simple_diff_from_matches<I>(
+    older: &[u8],
+    newer: &[u8],
+    matches: I,
+    out: &mut dyn Write,
+) -> Result<(), io::Error>
+where
+    I: IntoIterator<Item = Match>,
+{
+    write_matches(older, newer, matches, out, &Default::default())
+}
+
+#[cfg(feature = "enc")]
+fn write_matches<I>(
+    older: &[u8],
+    newer: &[u8],
+    matches: I,
+    out: &mut dyn Write,
+    diff_params: &DiffParams,
+) -> Result<(), io::Error>
+where
+    I: IntoIterator<Item = Match>,
+{
+    let mut w = enc::Writer::with_max_backref_distance(out, diff_params.max_backref_distance)?;
+    let mut translator = Translator::new(older, newer, |control| w.write(control))
+        .min_copy_len(diff_params.min_copy_len)
+        .merge_controls(diff_params.merge_controls);
+
+    let mut new_pos = 0;
+    for (i, m) in matches.into_iter().enumerate() {
+        let valid = m.add_new_start == new_pos
+            && m.add_new_start
+                .checked_add(m.add_length)
+                .is_some_and(|copy_start| copy_start <= m.copy_end)
+            && m.copy_end <= newer.len()
+            && m.add_old_start
+                .checked_add(m.add_length)
+                .is_some_and(|end| end <= older.len());
+        if !valid {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid match #{}: {:?}", i, m),
+            ));
+        }
+
+        if i == 0 && m.add_old_start != 0 {
+            // patches start reading the older file at its beginning
+            translator.translate(Match {
+                add_old_start: 0,
+                add_new_start: 0,
+                add_length: 0,
+                copy_end: 0,
+            })?;
+        }
+        new_pos = m.copy_end;
+        translator.translate(m)?;
+    }
+
+    if new_pos != newer.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "matches end at {}, but newer is {} bytes long",
+                new_pos,
+                newer.len()
+            ),
+        ));
+    }
+    translator.close()
+}
+
 pub fn assert_cycle(older: &[u8], newer: &[u8]) {
+    assert_cycle_with_params(older, newer, &Default::default())
+}
+
+pub fn assert_cycle_with_params(older: &[u8], newer: &[u8], params: &DiffParams) {
     let mut older_pos = 0_usize;
     let mut newer_pos = 0_usize;
 
@@ -487,12 +1390,11 @@ pub fn assert_cycle(older: &[u8], newer: &[u8]) {
         older_pos = (older_pos as i64 + control.seek) as usize;
 
         Ok(())
-    });
-
-    diff(older, newer, &Default::default(), |m| {
-        translator.translate(m)
     })
-    .unwrap();
+    .min_copy_len(params.min_copy_len)
+    .merge_controls(params.merge_controls);
+
+    diff(older, newer, params, |m| translator.translate(m)).unwrap();
 
     translator.close().unwrap();
 
@@ -505,7 +1407,8 @@ pub fn assert_cycle(older: &[u8], newer: &[u8]) {
 
 #[cfg(test)]
 mod tests {
-    use super::instructions::apply_instructions;
+    use super::instructions::{apply_edits, apply_instructions, EditParams};
+    use super::{Anchor, DiffParams};
     use proptest::prelude::*;
 
     #[test]
@@ -523,6 +1426,678 @@ mod tests {
         super::assert_cycle(&older[..], &newer[..]);
     }
 
+    fn matches(older: &[u8], newer: &[u8]) -> Vec<super::Match> {
+        let mut v = Vec::new();
+        super::diff(
+            older,
+            newer,
+            &Default::default(),
+            |m| -> Result<(), std::io::Error> {
+                v.push(m);
+                Ok(())
+            },
+        )
+        .unwrap();
+        v
+    }
+
+    #[test]
+    fn degenerate_inputs() {
+        let data = b"some data, some more data";
+
+        assert!(matches(&data[..], &[]).is_empty());
+        assert!(matches(&[], &[]).is_empty());
+
+        let m = matches(&[], &data[..]);
+        assert_eq!(m.len(), 1);
+        assert_eq!((m[0].add_length, m[0].copy_end), (0, data.len()));
+
+        let m = matches(&data[..], &data[..]);
+        assert_eq!(m.len(), 1);
+        assert_eq!((m[0].add_length, m[0].copy_end), (data.len(), data.len()));
+
+        for (older, newer) in [
+            (&data[..], &[][..]),
+            (&[][..], &data[..]),
+            (&data[..], &data[..]),
+        ]
+        .iter()
+        {
+            super::assert_cycle(older, newer);
+        }
+    }
+
+    #[test]
+    fn fast_identical_regions() {
+        let mut x = 1_u32;
+        let older: Vec<u8> = (0..300_000)
+            .map(|_| {
+                x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (x >> 16) as u8
+            })
+            .collect();
+        let mut newer = older.clone();
+        newer[70_000] ^= 0xFF;
+        newer[200_001] ^= 0xFF;
+        newer.extend_from_slice(b"trailing data");
+
+        assert_eq!(
+            super::identical_regions(&older, &newer, super::IDENTICAL_BLOCK_SIZE),
+            vec![0..65_536, 131_072..196_608]
+        );
+
+        // the gaps between identical regions line up with the older file
+        // too, so nothing seeks away from where the newer file is
+        let params = DiffParams::default().fast_identical_regions(true);
+        super::diff(&older, &newer, &params, |m| {
+            assert_eq!(m.add_old_start, m.add_new_start);
+            Ok::<_, ()>(())
+        })
+        .unwrap();
+
+        for params in [
+            DiffParams::default(),
+            DiffParams::new(2, Some(16 * 1024)).unwrap(),
+        ] {
+            let params = params.fast_identical_regions(true);
+            super::assert_cycle_with_params(&older, &newer, &params);
+        }
+    }
+
+    #[test]
+    fn skip_dissimilar() {
+        let mut x = 3_u32;
+        let mut random = |len: usize| -> Vec<u8> {
+            (0..len)
+                .map(|_| {
+                    x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                    (x >> 16) as u8
+                })
+                .collect()
+        };
+
+        // a shared section surrounded by unrelated data
+        let shared = random(8_000);
+        let older = [&random(30_000)[..], &shared[..], &random(10_000)[..]].concat();
+        let newer = [&random(20_000)[..], &shared[..], &random(20_000)[..]].concat();
+
+        let params = DiffParams::default().skip_dissimilar(true);
+        super::assert_cycle_with_params(&older, &newer, &params);
+
+        // the shared section is still found
+        let mut matched = 0;
+        super::diff(&older, &newer, &params, |m| -> Result<(), std::io::Error> {
+            matched += m.add_length;
+            Ok(())
+        })
+        .unwrap();
+        assert!(matched > 7_000, "only {} bytes matched", matched);
+
+        let params = DiffParams::new(2, Some(4096))
+            .unwrap()
+            .skip_dissimilar(true);
+        super::assert_cycle_with_params(&older, &newer, &params);
+    }
+
+    #[cfg(feature = "enc")]
+    #[test]
+    fn bailout() {
+        use super::{Bailout, PatchKind};
+        use std::io::Read;
+
+        let older: Vec<u8> = (0..20_000_u32).map(|i| (i * 7 % 251) as u8).collect();
+        let unrelated: Vec<u8> = (0..20_000_u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect();
+        let mut similar = older.clone();
+        similar[10_000] ^= 1;
+
+        let bailout = Bailout {
+            scanned: 0.1,
+            min_coverage: 0.5,
+        };
+        for (newer, kind) in [(&similar, PatchKind::Diff), (&unrelated, PatchKind::Stored)] {
+            let mut patch = Vec::new();
+            let res = super::simple_diff_with_bailout(
+                &older,
+                newer,
+                &mut patch,
+                &DiffParams::default(),
+                &bailout,
+            );
+            assert_eq!(res.unwrap(), kind);
+
+            let mut fresh = Vec::new();
+            bipatch::Reader::new(&patch[..], std::io::Cursor::new(&older[..]))
+                .unwrap()
+                .read_to_end(&mut fresh)
+                .unwrap();
+            assert!(&fresh == newer);
+        }
+    }
+
+    #[cfg(feature = "enc")]
+    #[test]
+    fn diff_files() {
+        let dir = std::env::temp_dir().join(format!("bidiff-diff-files-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (older_path, newer_path, patch_path) =
+            (dir.join("older"), dir.join("newer"), dir.join("patch"));
+
+        let older = b"the quick brown fox jumps over the lazy dog".repeat(10);
+        let newer = apply_instructions(&older, &[3, 40, 140, 9, 200, 17]);
+        std::fs::write(&older_path, &older).unwrap();
+        std::fs::write(&newer_path, &newer).unwrap();
+
+        super::simple_diff_files(
+            &older_path,
+            &newer_path,
+            &patch_path,
+            &DiffParams::default(),
+        )
+        .unwrap();
+        let mut expected = Vec::new();
+        super::simple_diff(&older, &newer, &mut expected).unwrap();
+        assert_eq!(std::fs::read(&patch_path).unwrap(), expected);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "enc")]
+    #[test]
+    fn shared_index() {
+        let older = b"the quick brown fox jumps over the lazy dog".repeat(100);
+        let newers = [
+            apply_instructions(&older, &[3, 40, 140, 9, 200, 17]),
+            apply_instructions(&older, &[100, 10, 129, 0, 90, 250]),
+            older.clone(),
+            Vec::new(),
+        ];
+        let params = DiffParams::default();
+        let index = super::OldIndex::new(&older, &params);
+
+        std::thread::scope(|s| {
+            for newer in &newers {
+                let index = &index;
+                let params = &params;
+                s.spawn(move || {
+                    let mut matches = Vec::new();
+                    super::diff_with_index(
+                        index,
+                        newer,
+                        params,
+                        |m| -> Result<(), std::io::Error> {
+                            matches.push(m);
+                            Ok(())
+                        },
+                    )
+                    .unwrap();
+
+                    let mut patch = Vec::new();
+                    super::simple_diff_from_matches(index.older(), newer, matches, &mut patch)
+                        .unwrap();
+                    let mut expected = Vec::new();
+                    super::simple_diff(index.older(), newer, &mut expected).unwrap();
+                    assert_eq!(patch, expected);
+                });
+            }
+        });
+    }
+
+    #[cfg(feature = "enc")]
+    #[test]
+    fn min_copy_len() {
+        use std::io::Read;
+
+        let older = b"the quick brown fox jumps over the lazy dog".repeat(20);
+        // short and long insertions in the middle
+        let newer = [
+            &older[..300],
+            b"0123456789",
+            &older[300..600],
+            &[b'x'; 40][..],
+            &older[600..],
+        ]
+        .concat();
+
+        let mut copies = Vec::new();
+        let mut translator =
+            super::Translator::new(&older, &newer, |c| -> Result<(), std::io::Error> {
+                copies.push(c.copy.len());
+                Ok(())
+            })
+            .min_copy_len(16);
+        super::diff(&older, &newer, &DiffParams::default(), |m| {
+            translator.translate(m)
+        })
+        .unwrap();
+        translator.close().unwrap();
+        assert!(
+            copies.iter().all(|&len| len == 0 || len >= 16),
+            "{:?}",
+            copies
+        );
+
+        let mut patch = Vec::new();
+        let params = DiffParams::default().min_copy_len(16);
+        super::simple_diff_with_params(&older, &newer, &mut patch, &params).unwrap();
+        let mut fresh = Vec::new();
+        bipatch::Reader::new(&patch[..], std::io::Cursor::new(&older[..]))
+            .unwrap()
+            .read_to_end(&mut fresh)
+            .unwrap();
+        assert_eq!(fresh, newer);
+    }
+
+    #[cfg(feature = "enc")]
+    #[test]
+    fn merge_controls() {
+        use std::io::Read;
+
+        let older = b"the quick brown fox jumps over the lazy dog".repeat(100);
+        let newer = [&older[..1000], b"0123456789", &older[1000..]].concat();
+        // chunk boundaries split matches that could have been one control
+        let params = DiffParams::new(1, Some(256)).unwrap();
+
+        let count_controls = |merge: bool| {
+            let mut count = 0;
+            let mut translator =
+                super::Translator::new(&older, &newer, |_| -> Result<(), std::io::Error> {
+                    count += 1;
+                    Ok(())
+                })
+                .merge_controls(merge);
+            super::diff(&older, &newer, &params, |m| translator.translate(m)).unwrap();
+            translator.close().unwrap();
+            count
+        };
+        assert!(count_controls(true) < count_controls(false));
+
+        let params = params.merge_controls(true);
+        super::assert_cycle_with_params(&older, &newer, &params);
+
+        let mut patch = Vec::new();
+        super::simple_diff_with_params(&older, &newer, &mut patch, &params).unwrap();
+        let mut fresh = Vec::new();
+        bipatch::Reader::new(&patch[..], std::io::Cursor::new(&older[..]))
+            .unwrap()
+            .read_to_end(&mut fresh)
+            .unwrap();
+        assert_eq!(fresh, newer);
+    }
+
+    #[test]
+    fn deadline() {
+        use std::time::Duration;
+
+        let older = b"the quick brown fox jumps over the lazy dog".repeat(1000);
+        // rotated, so nothing lines up without scanning
+        let newer = [&older[20_001..], &older[..20_001]].concat();
+
+        for params in [
+            DiffParams::default(),
+            DiffParams::new(2, Some(4096)).unwrap(),
+            DiffParams::default().fast_identical_regions(true),
+        ] {
+            // already expired: nothing gets matched, but the patch is valid
+            let params = params.deadline(Duration::ZERO);
+            super::assert_cycle_with_params(&older, &newer, &params);
+
+            let mut matched = 0;
+            super::diff(&older, &newer, &params, |m| -> Result<(), std::io::Error> {
+                matched += m.add_length;
+                Ok(())
+            })
+            .unwrap();
+            assert!(matched < older.len() / 2, "matched {} bytes", matched);
+        }
+
+        let params = DiffParams::default().deadline(Duration::from_secs(3600));
+        super::assert_cycle_with_params(&older, &newer, &params);
+    }
+
+    #[cfg(feature = "enc")]
+    #[test]
+    fn encode_on_another_thread() {
+        let older = b"the quick brown fox jumps over the lazy dog".repeat(40);
+        let newer = apply_instructions(&older, &[3, 40, 140, 9, 200, 17, 60, 90]);
+
+        let (tx, rx) = std::sync::mpsc::channel::<super::ControlBuf>();
+        let encoder = std::thread::spawn(move || {
+            let mut w = super::enc::Writer::new(Vec::new()).unwrap();
+            for c in rx {
+                w.write(&c).unwrap();
+            }
+            w.into_inner()
+        });
+        let mut translator = super::Translator::new(&older, &newer, |c| {
+            tx.send(c.into())
+                .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))
+        });
+        super::diff(&older, &newer, &DiffParams::default(), |m| {
+            translator.translate(m)
+        })
+        .unwrap();
+        translator.close().unwrap();
+        drop(tx);
+
+        let mut expected = Vec::new();
+        super::simple_diff(&older, &newer, &mut expected).unwrap();
+        assert_eq!(encoder.join().unwrap(), expected);
+    }
+
+    #[cfg(feature = "enc")]
+    #[test]
+    fn control_seeks() {
+        use super::Control;
+        use std::io::Read;
+
+        let older: Vec<u8> = (0..200_000_u32).map(|i| (i % 251) as u8).collect();
+        let mut w = super::enc::Writer::new(Vec::new()).unwrap();
+        for &(add, seek) in &[
+            // forward across most of the older file, relative
+            (10, 150_000),
+            // back to near its start, absolute
+            (10, -150_015),
+            (3, 0),
+        ] {
+            w.write(Control {
+                add: &vec![0; add],
+                copy: &[],
+                seek,
+            })
+            .unwrap();
+        }
+        let patch = w.into_inner();
+
+        let mut fresh = Vec::new();
+        bipatch::Reader::new(&patch[..], std::io::Cursor::new(&older[..]))
+            .unwrap()
+            .read_to_end(&mut fresh)
+            .unwrap();
+        let expected = [&older[..10], &older[150_010..150_020], &older[5..8]].concat();
+        assert_eq!(fresh, expected);
+    }
+
+    #[cfg(feature = "enc")]
+    #[test]
+    fn diff_from_matches() {
+        let older = b"the quick brown fox jumps over the lazy dog".repeat(4);
+        let mut newer = older.clone();
+        newer[50..60].copy_from_slice(b"0123456789");
+
+        let mut expected = Vec::new();
+        super::simple_diff(&older, &newer, &mut expected).unwrap();
+
+        let mut patch = Vec::new();
+        super::simple_diff_from_matches(&older, &newer, matches(&older, &newer), &mut patch)
+            .unwrap();
+        assert_eq!(patch, expected);
+
+        // a gap between matches
+        let gap = vec![
+            super::Match {
+                add_old_start: 0,
+                add_new_start: 0,
+                add_length: 10,
+                copy_end: 10,
+            },
+            super::Match {
+                add_old_start: 20,
+                add_new_start: 12,
+                add_length: 10,
+                copy_end: newer.len(),
+            },
+        ];
+        let err = super::simple_diff_from_matches(&older, &newer, gap, &mut Vec::new());
+        assert_eq!(err.unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+
+        // not covering all of newer
+        let short = matches(&older, &newer[..100]);
+        let err = super::simple_diff_from_matches(&older, &newer, short, &mut Vec::new());
+        assert_eq!(err.unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[cfg(feature = "enc")]
+    #[test]
+    fn size_cap() {
+        use super::enc::{CappedVec, SizeCapExceeded};
+
+        let older: Vec<u8> = (0..4096_u32).map(|i| (i * 7) as u8).collect();
+        let newer: Vec<u8> = (0..4096_u32).map(|i| (i * 13) as u8).collect();
+
+        let mut patch = Vec::new();
+        super::simple_diff(&older, &newer, &mut patch).unwrap();
+
+        let mut out = CappedVec::new(patch.len());
+        super::simple_diff(&older, &newer, &mut out).unwrap();
+        assert_eq!(out.into_inner(), patch);
+
+        let mut out = CappedVec::new(patch.len() / 2);
+        let err = super::simple_diff(&older, &newer, &mut out).unwrap_err();
+        assert_eq!(
+            SizeCapExceeded::from_io(&err),
+            Some(&SizeCapExceeded {
+                cap: patch.len() / 2
+            })
+        );
+    }
+
+    #[test]
+    fn anchors() {
+        let mut x = 7_u32;
+        let mut random = |len: usize| -> Vec<u8> {
+            (0..len)
+                .map(|_| {
+                    x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                    (x >> 16) as u8
+                })
+                .collect()
+        };
+
+        // two sections, which swap places and get some padding
+        let (text, data) = (random(20_000), random(12_000));
+        let older = [&text[..], &data[..]].concat();
+        let newer = [&data[..], &random(100)[..], &text[..]].concat();
+
+        let anchors = vec![
+            Anchor {
+                old_offset: 20_000,
+                new_offset: 0,
+            },
+            Anchor {
+                old_offset: 0,
+                new_offset: 12_100,
+            },
+        ];
+        // past the end of either file, ignored
+        let out_of_bounds = [
+            Anchor {
+                old_offset: 1_000_000,
+                new_offset: 16_000,
+            },
+            Anchor {
+                old_offset: 1_000_000,
+                new_offset: 1_000_000,
+            },
+        ];
+        let matches = |params: &DiffParams| {
+            let mut matches = Vec::new();
+            super::diff(&older, &newer, params, |m| {
+                matches.push((m.add_old_start, m.add_new_start, m.add_length, m.copy_end));
+                Ok::<_, ()>(())
+            })
+            .unwrap();
+            matches
+        };
+
+        for params in [
+            DiffParams::default(),
+            DiffParams::new(2, Some(4096)).unwrap(),
+            DiffParams::default().fast_identical_regions(true),
+        ] {
+            let with_out_of_bounds = [&anchors[..], &out_of_bounds[..]].concat();
+            let params_out_of_bounds = params.clone().anchors(with_out_of_bounds);
+            let params = params.anchors(anchors.clone());
+            super::assert_cycle_with_params(&older, &newer, &params);
+            assert_eq!(matches(&params_out_of_bounds), matches(&params));
+        }
+    }
+
+    #[cfg(feature = "enc")]
+    #[test]
+    fn max_backref_distance() {
+        let mut x = 11_u32;
+        let mut random = |len: usize| -> Vec<u8> {
+            (0..len)
+                .map(|_| {
+                    x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                    (x >> 16) as u8
+                })
+                .collect()
+        };
+
+        // the second half of the newer file repeats data from much earlier,
+        // with a few short insertions, which leave short copies
+        let (a, b, c) = (random(8_000), random(8_000), random(8_000));
+        let older = [&a[..], &b[..], &c[..]].concat();
+        let mut newer = [&a[..], &c[..]].concat();
+        for piece in a.chunks(500) {
+            newer.extend_from_slice(piece);
+            newer.extend_from_slice(&random(5));
+        }
+        newer.extend_from_slice(&b);
+
+        let distance = 4_000;
+        for params in [
+            DiffParams::default(),
+            DiffParams::new(2, Some(4096)).unwrap().merge_controls(true),
+            DiffParams::default().min_copy_len(16),
+        ] {
+            let params = params.max_backref_distance(distance);
+            super::assert_cycle_with_params(&older, &newer, &params);
+
+            let (mut pos, mut high) = (0_usize, 0_usize);
+            let mut translator =
+                super::Translator::new(&older, &newer, |c| -> Result<(), std::io::Error> {
+                    if !c.add.is_empty() {
+                        assert!(pos + distance >= high && pos <= high + distance);
+                        high = std::cmp::max(high, pos + c.add.len());
+                    }
+                    pos = (pos as i64 + c.add.len() as i64 + c.seek) as usize;
+                    Ok(())
+                })
+                .min_copy_len(params.min_copy_len)
+                .merge_controls(params.merge_controls);
+            super::diff(&older, &newer, &params, |m| translator.translate(m)).unwrap();
+            translator.close().unwrap();
+        }
+
+        // bytes read by folded copies count towards the window: after
+        // reading 1000..1060, 955 is too far behind
+        let mut window = super::BackrefWindow::new(100, 16, 10_000);
+        window.constrain(super::Match {
+            add_old_start: 0,
+            add_new_start: 0,
+            add_length: 1000,
+            copy_end: 1000,
+        });
+        let m = window.constrain(super::Match {
+            add_old_start: 1000,
+            add_new_start: 1000,
+            add_length: 50,
+            copy_end: 1060,
+        });
+        assert_eq!(m.add_length, 60);
+        let m = window.constrain(super::Match {
+            add_old_start: 955,
+            add_new_start: 1060,
+            add_length: 50,
+            copy_end: 1200,
+        });
+        assert_eq!((m.add_old_start, m.add_length), (1060, 0));
+
+        let mut patch = Vec::new();
+        let params = DiffParams::default().max_backref_distance(distance);
+        super::simple_diff_with_params(&older, &newer, &mut patch, &params).unwrap();
+        let r = bipatch::Reader::new(&patch[..], std::io::Cursor::new(&older[..])).unwrap();
+        assert_eq!(r.max_backref_distance(), Some(distance as u64));
+    }
+
+    #[cfg(feature = "research")]
+    #[test]
+    fn scan_policy() {
+        use super::{BsdiffPolicy, ScanPolicy};
+
+        /// Only stops at much longer matches
+        struct Picky;
+
+        impl ScanPolicy for Picky {
+            fn is_better(&self, length: usize, oldscore: usize) -> bool {
+                length > oldscore + 64
+            }
+
+            fn extension_score(&self, matching: isize, len: isize) -> isize {
+                BsdiffPolicy.extension_score(matching, len)
+            }
+        }
+
+        let older = b"the quick brown fox jumps over the lazy dog. ".repeat(100);
+        let newer = apply_instructions(&older, &[3, 40, 140, 9, 200, 17, 60, 90]);
+        for params in [
+            DiffParams::default().scan_policy(BsdiffPolicy),
+            DiffParams::default().scan_policy(Picky),
+            DiffParams::new(2, Some(1024)).unwrap().scan_policy(Picky),
+        ] {
+            super::assert_cycle_with_params(&older, &newer, &params);
+        }
+    }
+
+    #[test]
+    fn params_can_be_shared() {
+        fn assert_shareable<T: Clone + Send + Sync + std::fmt::Debug>() {}
+        assert_shareable::<DiffParams>();
+
+        let params = DiffParams::new(4, Some(1024)).unwrap().min_copy_len(8);
+        let debug = format!("{:?}", params.clone());
+        assert!(debug.contains("sort_partitions: 4"), "{}", debug);
+        assert!(debug.contains("min_copy_len: 8"), "{}", debug);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn params_from_config() {
+        let params: DiffParams = toml::from_str(
+            r#"
+            scan_chunk_size = 4096
+            merge_controls = true
+            anchors = [{ old_offset = 10, new_offset = 20 }]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(params.sort_partitions, 1);
+        assert_eq!(params.scan_chunk_size, Some(4096));
+        assert!(params.merge_controls);
+        assert_eq!(
+            params.anchors,
+            vec![Anchor {
+                old_offset: 10,
+                new_offset: 20
+            }]
+        );
+
+        let again: DiffParams = toml::from_str(&toml::to_string(&params).unwrap()).unwrap();
+        assert_eq!(format!("{:?}", again), format!("{:?}", params));
+
+        for invalid in ["sort_partitions = 0", "scan_chunk_size = 0", "typo = true"] {
+            assert!(
+                toml::from_str::<DiffParams>(invalid).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
     proptest! {
         #[test]
         fn cycle(older: [u8; 32], instructions: [u8; 32]) {
@@ -530,5 +2105,31 @@ mod tests {
             println!("{} => {}", older.len(), newer.len());
             super::assert_cycle(&older[..], &newer[..]);
         }
+
+        #[test]
+        fn cycle_edits(
+            older in prop::collection::vec(any::<u8>(), 0..4096),
+            instructions in prop::collection::vec(any::<u8>(), 0..80),
+        ) {
+            let params = EditParams {
+                max_block_len: 512,
+                ..Default::default()
+            };
+            let newer = apply_edits(&older[..], &instructions[..], &params);
+            super::assert_cycle(&older[..], &newer[..]);
+            super::assert_cycle_with_params(
+                &older[..],
+                &newer[..],
+                &DiffParams::default().skip_dissimilar(true),
+            );
+            super::assert_cycle_with_params(
+                &older[..],
+                &newer[..],
+                &DiffParams::new(1, Some(512))
+                    .unwrap()
+                    .min_copy_len(8)
+                    .merge_controls(true),
+            );
+        }
     }
 }
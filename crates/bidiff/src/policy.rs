@@ -0,0 +1,47 @@
+//! Scoring heuristics used by the scan loop.
+//!
+//! Only exposed with the `research` feature, for experimenting with
+//! alternative heuristics: regular builds always use [BsdiffPolicy].
+
+/// Decides which matches the scanner stops at, and how far matches are
+/// extended forwards and backwards.
+pub trait ScanPolicy: Send + Sync {
+    /// Whether a suffix array match of `length` bytes at the current
+    /// position should end the current stretch, given that `oldscore` of
+    /// those bytes already match with the previous alignment.
+    fn is_better(&self, length: usize, oldscore: usize) -> bool;
+
+    /// Score of extending a match over `len` bytes, `matching` of which
+    /// are equal in both files. Extensions go as far as the best score.
+    fn extension_score(&self, matching: isize, len: isize) -> isize;
+}
+
+/// The heuristics from the original bsdiff
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BsdiffPolicy;
+
+impl ScanPolicy for BsdiffPolicy {
+    #[inline(always)]
+    fn is_better(&self, length: usize, oldscore: usize) -> bool {
+        let significantly_better = length > oldscore + 8;
+        let same_length = length == oldscore && length != 0;
+        same_length || significantly_better
+    }
+
+    #[inline(always)]
+    fn extension_score(&self, matching: isize, len: isize) -> isize {
+        matching * 2 - len
+    }
+}
+
+impl<P: ScanPolicy + ?Sized> ScanPolicy for &P {
+    #[inline(always)]
+    fn is_better(&self, length: usize, oldscore: usize) -> bool {
+        (**self).is_better(length, oldscore)
+    }
+
+    #[inline(always)]
+    fn extension_score(&self, matching: isize, len: isize) -> isize {
+        (**self).extension_score(matching, len)
+    }
+}
@@ -0,0 +1,302 @@
+//! Diffing inputs with gaps, like snapshots of a process's memory or of
+//! flash (requires the `enc` feature).
+//!
+//! Each side is a list of `(offset, data)` segments. Patches address the
+//! older side by offset, and never read its gaps, so they apply to anything
+//! that can seek to those offsets: `/proc/<pid>/mem`, a flash device, or a
+//! snapshot file laid out at the same offsets. They produce the newer
+//! side's segments back to back; keeping track of where each one goes is up
+//! to the caller, like for the rest of the snapshot's layout:
+//!
+//! ```no_run
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let (text, data, new_text, new_data) = (Vec::new(), Vec::new(), Vec::new(), Vec::new());
+//! use bidiff::{segments::simple_diff_segments, DiffParams};
+//!
+//! let older = [(0x40_0000, &text[..]), (0x60_0000, &data[..])];
+//! let newer = [(0x40_0000, &new_text[..]), (0x61_0000, &new_data[..])];
+//! let mut patch = Vec::new();
+//! simple_diff_segments(&older, &newer, &mut patch, &DiffParams::default())?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{diff, enc, Anchor, Control, ControlBuf, DiffParams, Translator};
+use std::{
+    cmp::min,
+    io::{self, Write},
+};
+
+/// A segment of the older side, in the buffer they're concatenated into
+struct OldSegment {
+    /// where it starts in the concatenated buffer
+    start: usize,
+    len: usize,
+    offset: u64,
+}
+
+/// Diff `older` and `newer`, lists of `(offset, data)` segments, and write
+/// the patch to `out`.
+///
+/// Segments of `older` can come in any order, but must not overlap, and
+/// must end by `i64::MAX`. Segments of `newer` are written in the order
+/// they come in. Where both sides have a segment at the same offset, they
+/// are anchored to each other (see [DiffParams::anchors]), which replaces
+/// any anchors in `params`. `max_backref_distance` isn't supported: reads
+/// jump across gaps, whose size the diff doesn't see.
+pub fn simple_diff_segments(
+    older: &[(u64, &[u8])],
+    newer: &[(u64, &[u8])],
+    out: &mut dyn Write,
+    params: &DiffParams,
+) -> Result<(), io::Error> {
+    let mut sorted: Vec<_> = older.iter().filter(|(_, data)| !data.is_empty()).collect();
+    sorted.sort_by_key(|(offset, _)| *offset);
+    let mut end = 0_u64;
+    for (offset, data) in &sorted {
+        if *offset < end {
+            return Err(invalid(format!(
+                "older segment at {:#x} overlaps the one before it",
+                offset
+            )));
+        }
+        end = offset
+            .checked_add(data.len() as u64)
+            .filter(|&end| end <= i64::MAX as u64)
+            .ok_or_else(|| invalid(format!("older segment at {:#x} is too long", offset)))?;
+    }
+
+    let mut obuf = Vec::with_capacity(sorted.iter().map(|(_, data)| data.len()).sum());
+    let mut segments = Vec::with_capacity(sorted.len());
+    for (offset, data) in sorted {
+        segments.push(OldSegment {
+            start: obuf.len(),
+            len: data.len(),
+            offset: *offset,
+        });
+        obuf.extend_from_slice(data);
+    }
+
+    let mut nbuf = Vec::with_capacity(newer.iter().map(|(_, data)| data.len()).sum());
+    let mut anchors = Vec::new();
+    for (offset, data) in newer {
+        if let Some(s) = segments.iter().find(|s| s.offset == *offset) {
+            anchors.push(Anchor {
+                old_offset: s.start,
+                new_offset: nbuf.len(),
+            });
+        }
+        nbuf.extend_from_slice(data);
+    }
+
+    let mut params = params.clone().anchors(anchors);
+    params.max_backref_distance = None;
+
+    let mut w = SegmentWriter {
+        writer: enc::Writer::new(out)?,
+        segments: &segments,
+        pending: None,
+        pending_end: 0,
+        pos: 0,
+    };
+    let mut translator = Translator::new(&obuf, &nbuf, |control| w.write(control))
+        .min_copy_len(params.min_copy_len)
+        .merge_controls(params.merge_controls);
+    diff(&obuf, &nbuf, &params, |m| translator.translate(m))?;
+    translator.close()?;
+    w.finish()
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+/// Rewrites controls over the concatenated older segments into controls
+/// over their offsets, splitting adds that span several segments.
+///
+/// The last control is held back, so that its seek can be set once the
+/// next add tells where to go.
+struct SegmentWriter<'a, W>
+where
+    W: Write,
+{
+    writer: enc::Writer<W>,
+    segments: &'a [OldSegment],
+    /// the last control, whose seek isn't known yet
+    pending: Option<ControlBuf>,
+    /// offset in the older side where `pending`'s add ends
+    pending_end: u64,
+    /// position in the concatenated older segments
+    pos: usize,
+}
+
+impl<'a, W> SegmentWriter<'a, W>
+where
+    W: Write,
+{
+    fn write(&mut self, c: &Control) -> io::Result<()> {
+        let mut add = c.add;
+        while !add.is_empty() {
+            let i = self
+                .segments
+                .partition_point(|s| s.start + s.len <= self.pos);
+            let s = &self.segments[i];
+            let n = min(add.len(), s.start + s.len - self.pos);
+            self.add(s.offset + (self.pos - s.start) as u64, &add[..n])?;
+            add = &add[n..];
+            self.pos += n;
+        }
+
+        self.pending
+            .get_or_insert_with(Default::default)
+            .copy
+            .extend_from_slice(c.copy);
+        self.pos = (self.pos as i64 + c.seek) as usize;
+        Ok(())
+    }
+
+    /// Add `data` to what's read from `offset` in the older side
+    fn add(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        match self.pending.as_mut() {
+            Some(p) if p.copy.is_empty() && offset == self.pending_end => {
+                p.add.extend_from_slice(data);
+                self.pending_end += data.len() as u64;
+                return Ok(());
+            }
+            Some(p) => {
+                p.seek = offset as i64 - self.pending_end as i64;
+                self.writer.write(&*p)?;
+            }
+            // patches start reading the older side at 0
+            None if offset != 0 => {
+                self.writer.write(Control {
+                    add: &[],
+                    copy: &[],
+                    seek: offset as i64,
+                })?;
+            }
+            None => {}
+        }
+        self.pending = Some(ControlBuf {
+            add: data.to_vec(),
+            copy: Vec::new(),
+            seek: 0,
+        });
+        self.pending_end = offset + data.len() as u64;
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        if let Some(p) = self.pending.take() {
+            self.writer.write(&p)?;
+        }
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Seek, SeekFrom};
+
+    /// An older side that fails reads from its gaps
+    struct Sparse<'a> {
+        segments: &'a [(u64, &'a [u8])],
+        pos: u64,
+    }
+
+    impl<'a> Read for Sparse<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let pos = self.pos;
+            let (offset, data) = self
+                .segments
+                .iter()
+                .find(|(offset, data)| (*offset..*offset + data.len() as u64).contains(&pos))
+                .ok_or_else(|| io::Error::other(format!("read from gap at {:#x}", pos)))?;
+            let n = (&data[(pos - offset) as usize..]).read(buf)?;
+            self.pos += n as u64;
+            Ok(n)
+        }
+    }
+
+    impl<'a> Seek for Sparse<'a> {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            // the end is where the last segment ends
+            let end = self
+                .segments
+                .iter()
+                .map(|(offset, data)| offset + data.len() as u64)
+                .max()
+                .unwrap_or(0);
+            let (base, delta) = match pos {
+                SeekFrom::Start(n) => (n, 0),
+                SeekFrom::End(n) => (end, n),
+                SeekFrom::Current(n) => (self.pos, n),
+            };
+            self.pos = base.checked_add_signed(delta).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position")
+            })?;
+            Ok(self.pos)
+        }
+    }
+
+    fn apply(older: &[(u64, &[u8])], patch: &[u8]) -> Vec<u8> {
+        let old = Sparse {
+            segments: older,
+            pos: 0,
+        };
+        let mut fresh = Vec::new();
+        bipatch::Reader::new(patch, old)
+            .unwrap()
+            .read_to_end(&mut fresh)
+            .unwrap();
+        fresh
+    }
+
+    #[test]
+    fn segments() {
+        let text = b"the quick brown fox jumps over the lazy dog. ".repeat(50);
+        let data: Vec<u8> = (0..2000_u32).map(|i| (i * 7 % 251) as u8).collect();
+        let stack = b"0123456789".repeat(20);
+        let older: &[(u64, &[u8])] = &[(0x8000, &data), (0x1000, &text), (0x20_0000, &stack)];
+
+        let mut new_text = text.clone();
+        new_text[100..110].copy_from_slice(b"SLOW GREEN");
+        let mut new_data = data[..1500].to_vec();
+        new_data.extend_from_slice(&stack[..50]);
+        new_data.extend_from_slice(&text[2000..]);
+        // what's at either side of the gap between them, which the diff
+        // sees next to each other
+        let straddle = [&text[2000..], &data[..300]].concat();
+        for newer in [
+            &[(0x1000, &new_text[..]), (0x9000, &new_data[..])][..],
+            &[(0x9000, &new_data[..]), (0x1000, &new_text[..])],
+            &[(0x4000, &straddle[..])],
+            &[(0x1000, &[][..])],
+            &[],
+        ] {
+            let mut patch = Vec::new();
+            simple_diff_segments(older, newer, &mut patch, &DiffParams::default()).unwrap();
+            let expected: Vec<u8> = newer.iter().flat_map(|(_, d)| d.iter().copied()).collect();
+            assert_eq!(apply(older, &patch), expected);
+        }
+
+        // with no older segments at all
+        let mut patch = Vec::new();
+        let newer = [(0x1000, &text[..])];
+        simple_diff_segments(&[], &newer, &mut patch, &DiffParams::default()).unwrap();
+        assert_eq!(apply(&[], &patch), text);
+    }
+
+    #[test]
+    fn invalid_segments() {
+        let params = DiffParams::default();
+        for older in [
+            &[(0x1000, &[0; 16][..]), (0x100f, &[0; 16])][..],
+            &[(i64::MAX as u64, &[0; 16])],
+        ] {
+            let err = simple_diff_segments(older, &[], &mut Vec::new(), &params).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+}
@@ -0,0 +1,354 @@
+//! Diffing files that don't fit in memory at once, or that are produced
+//! on the fly (requires the `enc` feature).
+//!
+//! [PatchEncoder] accepts the newer file through `Write`, and diffs it
+//! against the older file one window at a time, so only the older file and
+//! a window of the newer file need to be in memory:
+//!
+//! ```no_run
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let (older, mut tar_stream, out) = (Vec::new(), std::io::empty(), std::io::sink());
+//! use bidiff::{stream::PatchEncoder, DiffParams};
+//!
+//! let params = DiffParams::default();
+//! let mut encoder = PatchEncoder::new(&older, &params, out)?;
+//! std::io::copy(&mut tar_stream, &mut encoder)?;
+//! encoder.finish()?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{diff_with_index, enc, ControlBuf, DiffParams, OldIndex, Translator};
+use std::io::{self, Read, Write};
+
+/// Default size of the windows the newer file is diffed in
+pub const DEFAULT_WINDOW_SIZE: usize = 16 * 1024 * 1024;
+
+/// Writes the controls of windows of the newer file, diffed separately,
+/// as a single patch.
+///
+/// The controls of each window assume reading the older file starts at
+/// the beginning of the part of it the window was diffed against. The last
+/// control of each window is held back, so that its seek can be rewritten
+/// to get there once the next window has something to write.
+struct WindowWriter<W>
+where
+    W: Write,
+{
+    writer: enc::Writer<W>,
+    /// the last control written, whose seek may still change
+    pending: Option<ControlBuf>,
+    /// position in the older file before `pending`
+    old_pos: i64,
+}
+
+impl<W> WindowWriter<W>
+where
+    W: Write,
+{
+    fn new(out: W) -> io::Result<Self> {
+        Ok(Self {
+            writer: enc::Writer::new(out)?,
+            pending: None,
+            old_pos: 0,
+        })
+    }
+
+    /// Diff `window` against `index`, which covers the older file from
+    /// `old_start` on.
+    fn diff(
+        &mut self,
+        index: &OldIndex,
+        old_start: usize,
+        window: &[u8],
+        params: &DiffParams,
+    ) -> io::Result<()> {
+        if window.is_empty() {
+            return Ok(());
+        }
+
+        if self.pending.is_none() && old_start != 0 {
+            self.pending = Some(ControlBuf::default());
+        }
+        if let Some(p) = self.pending.as_mut() {
+            p.seek = old_start as i64 - (self.old_pos + p.add.len() as i64);
+        }
+
+        let Self {
+            writer,
+            pending,
+            old_pos,
+        } = self;
+
+        let mut first = true;
+        let mut translator = Translator::new(index.older(), window, |c| -> io::Result<()> {
+            let first = std::mem::replace(&mut first, false);
+            match pending.as_mut() {
+                // a window that doesn't start reading the older file where
+                // its index starts begins with a seek-only control, which
+                // can be folded into the previous window's last control
+                Some(p) if first && c.add.is_empty() && c.copy.is_empty() => {
+                    p.seek += c.seek;
+                    return Ok(());
+                }
+                Some(p) => {
+                    writer.write(&*p)?;
+                    *old_pos += p.add.len() as i64 + p.seek;
+                }
+                None => {}
+            }
+            *pending = Some(c.into());
+            Ok(())
+        })
+        .min_copy_len(params.min_copy_len)
+        .merge_controls(params.merge_controls);
+        diff_with_index(index, window, params, |m| translator.translate(m))?;
+        translator.close()
+    }
+
+    fn finish(mut self) -> io::Result<W> {
+        if let Some(mut p) = self.pending.take() {
+            p.seek = 0;
+            self.writer.write(&p)?;
+        }
+        self.writer.flush()?;
+        Ok(self.writer.into_inner())
+    }
+}
+
+/// Writes a patch for a newer file written to it in pieces.
+///
+/// The newer file is diffed in windows of [PatchEncoder::window_size]
+/// bytes, each against the whole older file. Matches can't span windows,
+/// so patches are a little larger than with [simple_diff_with_params]
+/// (much like with a `scan_chunk_size`). Anchors and
+/// `fast_identical_regions` treat each window as if it started the newer
+/// file.
+///
+/// [simple_diff_with_params]: crate::simple_diff_with_params
+pub struct PatchEncoder<'a, W>
+where
+    W: Write,
+{
+    index: OldIndex<'a>,
+    params: &'a DiffParams,
+    window: Vec<u8>,
+    window_size: usize,
+    writer: WindowWriter<W>,
+}
+
+impl<'a, W> PatchEncoder<'a, W>
+where
+    W: Write,
+{
+    /// Sort `older` and write the patch header to `out`.
+    pub fn new(older: &'a [u8], params: &'a DiffParams, out: W) -> io::Result<Self> {
+        Ok(Self {
+            index: OldIndex::new(older, params),
+            params,
+            window: Vec::new(),
+            window_size: DEFAULT_WINDOW_SIZE,
+            writer: WindowWriter::new(out)?,
+        })
+    }
+
+    /// Diff the newer file in windows of `size` bytes (at least 1). Larger
+    /// windows find more matches, but take more memory.
+    pub fn window_size(mut self, size: usize) -> Self {
+        self.window_size = std::cmp::max(1, size);
+        self
+    }
+
+    /// Diff what's left of the newer file, and return the patch's writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.diff_window()?;
+        self.writer.finish()
+    }
+
+    fn diff_window(&mut self) -> io::Result<()> {
+        self.writer
+            .diff(&self.index, 0, &self.window, self.params)?;
+        self.window.clear();
+        Ok(())
+    }
+}
+
+/// Diff files too large to sort in one go, in aligned windows of
+/// `window_size` bytes, and write the patch to `out`.
+///
+/// Each window of `newer` is diffed against the window of `older` at the
+/// same offset, widened by `overlap` bytes on both sides, so only one
+/// window of each (and a suffix array over the older one) is in memory at
+/// a time. This suits inputs whose layout mostly stays in place, like disk
+/// images: data that moved further than `overlap` bytes away is stored as
+/// new data. An overlap about as large as data shifts by (the size of the
+/// insertions minus the deletions before it) recovers most of what
+/// windowing costs otherwise. The windows of `newer` themselves don't
+/// overlap, so no match is found twice. Anchors and
+/// `fast_identical_regions` treat each pair of windows as whole files.
+///
+/// The patch is a regular patch: windows don't show up in it.
+///
+/// # Panics
+///
+/// Panics if `window_size` is 0.
+pub fn simple_diff_windowed<O, N>(
+    mut older: O,
+    mut newer: N,
+    out: &mut dyn Write,
+    params: &DiffParams,
+    window_size: usize,
+    overlap: usize,
+) -> io::Result<()>
+where
+    O: Read,
+    N: Read,
+{
+    assert!(window_size > 0, "window size cannot be 0");
+
+    let mut writer = WindowWriter::new(out)?;
+    let mut new_window = Vec::new();
+    // the part of the older file read so far and still needed, which
+    // starts at `old_start`
+    let mut old_window = Vec::new();
+    let mut old_start = 0_usize;
+    let mut new_start = 0_usize;
+    loop {
+        new_window.clear();
+        (&mut newer)
+            .take(window_size as u64)
+            .read_to_end(&mut new_window)?;
+        if new_window.is_empty() {
+            break;
+        }
+
+        let lo = new_start.saturating_sub(overlap);
+        let hi = new_start + window_size + overlap;
+        let read = old_start + old_window.len();
+        if read < hi {
+            (&mut older)
+                .take((hi - read) as u64)
+                .read_to_end(&mut old_window)?;
+        }
+        // `older` may have ended before `lo`
+        let drop = std::cmp::min(lo.saturating_sub(old_start), old_window.len());
+        old_window.drain(..drop);
+        old_start += drop;
+
+        let index = OldIndex::new(&old_window, params);
+        writer.diff(&index, old_start, &new_window, params)?;
+        new_start += new_window.len();
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+impl<'a, W> Write for PatchEncoder<'a, W>
+where
+    W: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = std::cmp::min(buf.len(), self.window_size - self.window.len());
+        self.window.extend_from_slice(&buf[..n]);
+        if self.window.len() == self.window_size {
+            self.diff_window()?;
+        }
+        Ok(n)
+    }
+
+    /// Flushes the patch's writer. The window isn't diffed until it's
+    /// full, or [PatchEncoder::finish] is called.
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instructions::{apply_edits, EditParams};
+
+    fn apply(older: &[u8], patch: &[u8]) -> Vec<u8> {
+        let mut fresh = Vec::new();
+        bipatch::Reader::new(patch, io::Cursor::new(older))
+            .unwrap()
+            .read_to_end(&mut fresh)
+            .unwrap();
+        fresh
+    }
+
+    #[test]
+    fn windows() {
+        let older = b"the quick brown fox jumps over the lazy dog".repeat(200);
+        let instructions: Vec<u8> = (0..200_u32).map(|i| (i * 37) as u8).collect();
+        let newer = apply_edits(&older, &instructions, &EditParams::default());
+        let params = DiffParams::default();
+
+        for &window_size in &[1, 100, 1000, newer.len(), newer.len() * 2] {
+            let mut encoder = PatchEncoder::new(&older, &params, Vec::new())
+                .unwrap()
+                .window_size(window_size);
+            // write in pieces that don't line up with windows
+            for piece in newer.chunks(333) {
+                encoder.write_all(piece).unwrap();
+            }
+            let patch = encoder.finish().unwrap();
+            assert_eq!(apply(&older, &patch), newer, "window size {}", window_size);
+
+            if window_size >= newer.len() {
+                let mut expected = Vec::new();
+                crate::simple_diff(&older, &newer, &mut expected).unwrap();
+                assert_eq!(patch, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn aligned_windows() {
+        let older = b"the quick brown fox jumps over the lazy dog".repeat(200);
+        let instructions: Vec<u8> = (0..200_u32).map(|i| (i * 37) as u8).collect();
+        let newer = apply_edits(&older, &instructions, &EditParams::default());
+        let params = DiffParams::default();
+
+        // newer longer and shorter than older
+        for newer in [&newer[..], &newer[..older.len() / 2]] {
+            for &(window_size, overlap) in
+                &[(1, 0), (1000, 0), (1000, 100), (4096, 5000), (1 << 20, 0)]
+            {
+                let mut patch = Vec::new();
+                simple_diff_windowed(&older[..], newer, &mut patch, &params, window_size, overlap)
+                    .unwrap();
+                assert_eq!(
+                    apply(&older, &patch),
+                    newer,
+                    "window size {}, overlap {}",
+                    window_size,
+                    overlap
+                );
+
+                if window_size >= std::cmp::max(older.len(), newer.len()) {
+                    let mut expected = Vec::new();
+                    crate::simple_diff(&older, newer, &mut expected).unwrap();
+                    assert_eq!(patch, expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn empty_inputs() {
+        let params = DiffParams::default();
+        for (older, newer) in [(&b""[..], &b""[..]), (b"", b"fresh"), (b"stale", b"")] {
+            let mut encoder = PatchEncoder::new(older, &params, Vec::new())
+                .unwrap()
+                .window_size(2);
+            encoder.write_all(newer).unwrap();
+            let patch = encoder.finish().unwrap();
+            assert_eq!(apply(older, &patch), newer);
+
+            let mut patch = Vec::new();
+            simple_diff_windowed(older, newer, &mut patch, &params, 2, 1).unwrap();
+            assert_eq!(apply(older, &patch), newer);
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! Patch size regression suite.
+//!
+//! Diffs a fixed corpus of generated file pairs and checks that the
+//! compressed patch sizes stay within a tolerance of recorded golden
+//! values, so changes to the scanner that silently bloat patches get
+//! caught. Run with `--nocapture` to see the measured sizes, and update
+//! `GOLDEN` when a size change is intended.
+
+mod common;
+
+use bidiff::{
+    instructions::{apply_edits, EditParams},
+    DiffParams,
+};
+use common::XorShift;
+use flate2::{write::DeflateEncoder, Compression};
+use std::io::{Cursor, Read, Write};
+
+/// Allowed deviation from the golden size, in percent
+const TOLERANCE_PERCENT: f64 = 2.0;
+
+/// Deflate-compressed patch sizes, in bytes
+const GOLDEN: &[(&str, usize)] = &[
+    ("random-64k", 323),
+    ("text-256k", 625),
+    ("text-256k-chunked", 714),
+    ("text-256k-chunked-merged", 625),
+    ("sparse-1m", 1935),
+];
+
+struct Case {
+    name: &'static str,
+    older: Vec<u8>,
+    newer: Vec<u8>,
+    params: DiffParams,
+}
+
+/// Low-entropy, text-like data: words drawn from a small vocabulary
+fn text(rng: &mut XorShift, len: usize) -> Vec<u8> {
+    const WORDS: &[&str] = &[
+        "patch", "older", "newer", "suffix", "array", "scan", "match", "seek", "the", "a", "of",
+        "copy", "add", "chunk", "\n",
+    ];
+    let mut v = Vec::with_capacity(len + 16);
+    while v.len() < len {
+        let word = WORDS[(rng.next_u64() % WORDS.len() as u64) as usize];
+        v.extend_from_slice(word.as_bytes());
+        v.push(b' ');
+    }
+    v.truncate(len);
+    v
+}
+
+/// Mostly zeroes with scattered random records, like a disk image
+fn sparse(rng: &mut XorShift, len: usize) -> Vec<u8> {
+    let mut v = vec![0u8; len];
+    for _ in 0..len / 4096 {
+        let pos = (rng.next_u64() % (len as u64 - 256)) as usize;
+        rng.fill(&mut v[pos..pos + 256]);
+    }
+    v
+}
+
+fn edits(rng: &mut XorShift, older: &[u8], count: usize) -> Vec<u8> {
+    let mut instructions = vec![0u8; count * 5];
+    rng.fill(&mut instructions);
+    apply_edits(older, &instructions, &EditParams::default())
+}
+
+fn corpus() -> Vec<Case> {
+    let mut rng = XorShift(0x9E37_79B9_7F4A_7C15);
+    let mut cases = Vec::new();
+
+    let mut older = vec![0u8; 64 * 1024];
+    rng.fill(&mut older);
+    let newer = edits(&mut rng, &older, 16);
+    cases.push(Case {
+        name: "random-64k",
+        older,
+        newer,
+        params: Default::default(),
+    });
+
+    let older = text(&mut rng, 256 * 1024);
+    let newer = edits(&mut rng, &older, 32);
+    cases.push(Case {
+        name: "text-256k",
+        older: older.clone(),
+        newer: newer.clone(),
+        params: Default::default(),
+    });
+    cases.push(Case {
+        name: "text-256k-chunked",
+        older: older.clone(),
+        newer: newer.clone(),
+        params: DiffParams::new(2, Some(32 * 1024)).unwrap(),
+    });
+    cases.push(Case {
+        name: "text-256k-chunked-merged",
+        older,
+        newer,
+        params: DiffParams::new(2, Some(32 * 1024))
+            .unwrap()
+            .merge_controls(true),
+    });
+
+    let older = sparse(&mut rng, 1024 * 1024);
+    let newer = edits(&mut rng, &older, 32);
+    cases.push(Case {
+        name: "sparse-1m",
+        older,
+        newer,
+        params: Default::default(),
+    });
+
+    cases
+}
+
+fn compressed_patch_size(case: &Case) -> usize {
+    let mut patch = Vec::new();
+    bidiff::simple_diff_with_params(&case.older, &case.newer, &mut patch, &case.params).unwrap();
+
+    let mut fresh = Vec::new();
+    bipatch::Reader::new(&patch[..], Cursor::new(&case.older[..]))
+        .unwrap()
+        .read_to_end(&mut fresh)
+        .unwrap();
+    assert!(fresh == case.newer, "{}: patch should cycle", case.name);
+
+    let mut e = DeflateEncoder::new(Vec::new(), Compression::best());
+    e.write_all(&patch).unwrap();
+    e.finish().unwrap().len()
+}
+
+#[test]
+fn patch_sizes_match_golden() {
+    let mut failures = Vec::new();
+
+    for case in corpus() {
+        let size = compressed_patch_size(&case);
+        let golden = GOLDEN
+            .iter()
+            .find(|(name, _)| *name == case.name)
+            .map(|(_, size)| *size)
+            .unwrap_or_else(|| panic!("{}: no golden size recorded", case.name));
+
+        let delta = (size as f64 - golden as f64) / golden as f64 * 100.0;
+        println!(
+            "{:20} golden {:>8} B, got {:>8} B ({:+.2}%)",
+            case.name, golden, size, delta
+        );
+        if delta.abs() > TOLERANCE_PERCENT {
+            failures.push(case.name);
+        }
+    }
+
+    assert!(
+        failures.is_empty(),
+        "patch sizes outside of {}% tolerance: {:?}",
+        TOLERANCE_PERCENT,
+        failures
+    );
+}
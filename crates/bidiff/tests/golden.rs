@@ -0,0 +1,120 @@
+//! Wire format snapshots.
+//!
+//! Each fixture is an older file, a newer file and the patch between them,
+//! in a directory for the version of the format it was written in.
+//!
+//! In `tests/fixtures/v1` (version 0x1000):
+//!
+//! - `hello` and `moved` were written by this repository's encoder as of
+//!   its initial commit
+//! - `empty` and `new-only` were written by the 0x1000 encoder once it no
+//!   longer panicked on empty older files
+//! - `rearranged` was written by hand, to cover negative seeks
+//!
+//! In `tests/fixtures/v1.1` (version 0x1001, which adds the maximum
+//! backreference distance to the header), the same fixtures, as written
+//! by the encoder that introduced it (`rearranged` by hand again).
+//!
+//! In `tests/fixtures/v1.2` (version 0x1002, where seeks can be absolute),
+//! the same again. `rearranged` seeks back to the start with an absolute
+//! seek.
+//!
+//! The encoder must keep producing the latest version's patches byte for
+//! byte, and bipatch must keep applying all of them. When the wire format
+//! changes on purpose, bump the version and add fixtures next to these
+//! instead of replacing them.
+
+use std::io::{Cursor, Read};
+
+struct Fixture {
+    name: &'static str,
+    older: &'static [u8],
+    newer: &'static [u8],
+    patch: &'static [u8],
+    /// Whether the current encoder should produce `patch` from the inputs
+    encoded: bool,
+}
+
+macro_rules! fixture {
+    ($version:literal, $name:literal, $encoded:expr) => {
+        Fixture {
+            name: concat!($version, "/", $name),
+            older: include_bytes!(concat!("fixtures/", $version, "/", $name, ".old")),
+            newer: include_bytes!(concat!("fixtures/", $version, "/", $name, ".new")),
+            patch: include_bytes!(concat!("fixtures/", $version, "/", $name, ".patch")),
+            encoded: $encoded,
+        }
+    };
+}
+
+const FIXTURES: &[Fixture] = &[
+    fixture!("v1", "empty", false),
+    fixture!("v1", "new-only", false),
+    fixture!("v1", "hello", false),
+    fixture!("v1", "moved", false),
+    fixture!("v1", "rearranged", false),
+    fixture!("v1.1", "empty", false),
+    fixture!("v1.1", "new-only", false),
+    fixture!("v1.1", "hello", false),
+    fixture!("v1.1", "moved", false),
+    fixture!("v1.1", "rearranged", false),
+    fixture!("v1.2", "empty", true),
+    fixture!("v1.2", "new-only", true),
+    fixture!("v1.2", "hello", true),
+    fixture!("v1.2", "moved", true),
+    fixture!("v1.2", "rearranged", false),
+];
+
+fn fixture(name: &str) -> &'static Fixture {
+    FIXTURES.iter().find(|f| f.name == name).unwrap()
+}
+
+#[test]
+fn hello_layout() {
+    #[rustfmt::skip]
+    let expected: &[u8] = &[
+        0xDF, 0xB1, 0x00, 0x00, // magic
+        0x00, 0x10, 0x00, 0x00, // version
+        5, 0, 0, 0, 0, 0, // add "hello" (identical to the older file)
+        9, b',', b' ', b'w', b'o', b'r', b'l', b'd', b'!', b'\n', // copy
+        0, // seek
+    ];
+    assert_eq!(fixture("v1/hello").patch, expected);
+
+    #[rustfmt::skip]
+    let expected: &[u8] = &[
+        0xDF, 0xB1, 0x00, 0x00, // magic
+        0x01, 0x10, 0x00, 0x00, // version
+        0, 0, 0, 0, 0, 0, 0, 0, // no maximum backreference distance
+        5, 0, 0, 0, 0, 0, // add "hello" (identical to the older file)
+        9, b',', b' ', b'w', b'o', b'r', b'l', b'd', b'!', b'\n', // copy
+        0, // seek
+    ];
+    assert_eq!(fixture("v1.1/hello").patch, expected);
+
+    // seeks are encoded differently, but a seek of 0 is still 0
+    let mut expected = expected.to_vec();
+    expected[4] = 0x02;
+    assert_eq!(fixture("v1.2/hello").patch, &expected[..]);
+}
+
+#[test]
+fn encoder_output_is_unchanged() {
+    for f in FIXTURES.iter().filter(|f| f.encoded) {
+        let mut patch = Vec::new();
+        bidiff::simple_diff(f.older, f.newer, &mut patch).unwrap();
+        assert_eq!(patch, f.patch, "{}: patch differs from fixture", f.name);
+    }
+}
+
+#[test]
+fn fixtures_apply() {
+    for f in FIXTURES {
+        let mut fresh = Vec::new();
+        bipatch::Reader::new(f.patch, Cursor::new(f.older))
+            .unwrap()
+            .read_to_end(&mut fresh)
+            .unwrap();
+        assert_eq!(fresh, f.newer, "{}: wrong output", f.name);
+    }
+}
@@ -0,0 +1,188 @@
+//! Soak test on large generated inputs, ignored by default.
+//!
+//! Generates hundreds of megabytes of structured data, edits it all over,
+//! then diffs it with a range of parameters and checks that applying each
+//! patch reproduces the newer file (by hash). This is for bugs only large
+//! inputs reach, like chunk and window boundary bugs or 32-bit overflows.
+//! Run it in release mode:
+//!
+//! ```text
+//! cargo test --release -p bidiff --features instructions --test soak -- --ignored --nocapture
+//! ```
+//!
+//! `BIDIFF_SOAK_MB` sets the size of the older file (default: 256), and
+//! `BIDIFF_SOAK_SEED` the seed the inputs are generated from.
+
+mod common;
+
+use bidiff::{
+    instructions::{apply_edits, generate, EditParams},
+    stream, DiffParams,
+};
+use common::XorShift;
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::Hasher,
+    io::{self, Cursor, Write},
+    time::Instant,
+};
+
+/// Hashes what's written to it, so outputs don't have to be kept around
+struct HashWriter {
+    hasher: DefaultHasher,
+    len: u64,
+}
+
+impl Write for HashWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.hasher.write(buf);
+        self.len += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn hash(data: &[u8]) -> (u64, u64) {
+    let mut w = HashWriter {
+        hasher: DefaultHasher::new(),
+        len: 0,
+    };
+    w.write_all(data).unwrap();
+    (w.hasher.finish(), w.len)
+}
+
+fn env_or(name: &str, default: u64) -> u64 {
+    match std::env::var(name) {
+        Ok(v) => v
+            .parse()
+            .unwrap_or_else(|_| panic!("{} should be a number, got {:?}", name, v)),
+        Err(_) => default,
+    }
+}
+
+/// Edit every region of `older`, so each chunk and window has changes
+fn edit_regions(rng: &mut XorShift, older: &[u8]) -> Vec<u8> {
+    let region_len = std::cmp::max(older.len() / 256, 4096);
+    let params = EditParams {
+        max_block_len: region_len / 8,
+        ..Default::default()
+    };
+
+    let mut newer = Vec::with_capacity(older.len() + older.len() / 16);
+    for region in older.chunks(region_len) {
+        let mut instructions = vec![0u8; 5 * (1 + rng.next_u64() as usize % 8)];
+        rng.fill(&mut instructions);
+        newer.extend_from_slice(&apply_edits(region, &instructions, &params));
+    }
+
+    // and move a few blocks far away, across regions
+    for _ in 0..4 {
+        let len = region_len * (1 + rng.next_u64() as usize % 4);
+        if newer.len() <= len {
+            break;
+        }
+        let from = rng.next_u64() as usize % (newer.len() - len);
+        let block: Vec<u8> = newer.drain(from..from + len).collect();
+        let to = rng.next_u64() as usize % newer.len();
+        newer.splice(to..to, block);
+    }
+    newer
+}
+
+fn check(name: &str, older: &[u8], expected: (u64, u64), patch: &[u8], started: Instant) {
+    let mut fresh = HashWriter {
+        hasher: DefaultHasher::new(),
+        len: 0,
+    };
+    bipatch::apply(patch, Cursor::new(older), &mut fresh)
+        .unwrap_or_else(|e| panic!("{}: patch should apply: {}", name, e));
+    assert_eq!(
+        (fresh.hasher.finish(), fresh.len),
+        expected,
+        "{}: wrong output",
+        name
+    );
+    println!(
+        "{:28} patch {:>12} B in {:?}",
+        name,
+        patch.len(),
+        started.elapsed()
+    );
+}
+
+fn soak(len: usize, seed: u64) {
+    let mut rng = XorShift(seed | 1);
+
+    let mut instructions = vec![0u8; 4 * 4096];
+    rng.fill(&mut instructions);
+    let older = generate(&instructions, len);
+    let newer = edit_regions(&mut rng, &older);
+    let expected = hash(&newer);
+    println!(
+        "seed {}: older {} B, newer {} B",
+        seed,
+        older.len(),
+        newer.len()
+    );
+
+    let chunk = std::cmp::max(len / 64, 1);
+    let cases = vec![
+        ("default", DiffParams::default()),
+        (
+            "partitioned, chunked",
+            DiffParams::new(4, Some(chunk)).unwrap(),
+        ),
+        (
+            "chunked, merged, folded",
+            DiffParams::new(1, Some(chunk))
+                .unwrap()
+                .merge_controls(true)
+                .min_copy_len(8),
+        ),
+        (
+            "fast",
+            DiffParams::new(4, Some(chunk * 4))
+                .unwrap()
+                .fast_identical_regions(true)
+                .skip_dissimilar(true),
+        ),
+    ];
+    for (name, params) in cases {
+        let started = Instant::now();
+        let mut patch = Vec::new();
+        bidiff::simple_diff_with_params(&older, &newer, &mut patch, &params).unwrap();
+        check(name, &older, expected, &patch, started);
+    }
+
+    let started = Instant::now();
+    let mut patch = Vec::new();
+    let window = std::cmp::max(len / 4, 1);
+    stream::simple_diff_windowed(
+        &older[..],
+        &newer[..],
+        &mut patch,
+        &DiffParams::default(),
+        window,
+        window / 64,
+    )
+    .unwrap();
+    check("windowed", &older, expected, &patch, started);
+}
+
+/// The soak test at a size that runs along with the other tests, to keep
+/// the harness itself working
+#[test]
+fn soak_small() {
+    soak(256 * 1024, 1);
+}
+
+#[test]
+#[ignore]
+fn soak_large() {
+    let mb = env_or("BIDIFF_SOAK_MB", 256);
+    let seed = env_or("BIDIFF_SOAK_SEED", 0x5EED);
+    soak(mb as usize * 1024 * 1024, seed);
+}
@@ -0,0 +1,99 @@
+//! Applies a patch with all memory coming from a fixed, static arena, the
+//! way a small device without a heap allocator would.
+//!
+//! bipatch needs `std::io`, so it doesn't run on `no_std` targets, but
+//! [bipatch::Reader] only ever allocates its 4 KiB buffer: memory use
+//! doesn't depend on the size of the inputs. The older file is read from
+//! a slice (in flash, say), and the output is written into a fixed buffer.
+//!
+//! Run with `cargo run -p bipatch --example tiny_apply`.
+
+use std::{
+    alloc::{GlobalAlloc, Layout},
+    cell::UnsafeCell,
+    io::{Cursor, Read},
+    ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// All the heap the program gets, including what the standard library
+/// allocates at startup
+const ARENA_SIZE: usize = 32 * 1024;
+
+/// Hands out memory from a static array, and never reuses it
+struct Arena {
+    memory: UnsafeCell<[u8; ARENA_SIZE]>,
+    used: AtomicUsize,
+}
+
+// SAFETY: `used` hands each part of `memory` out only once
+unsafe impl Sync for Arena {}
+
+unsafe impl GlobalAlloc for Arena {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let base = self.memory.get() as usize;
+        let mut used = self.used.load(Ordering::Relaxed);
+        loop {
+            let start = (base + used).next_multiple_of(layout.align()) - base;
+            let end = match start.checked_add(layout.size()) {
+                Some(end) if end <= ARENA_SIZE => end,
+                _ => return ptr::null_mut(),
+            };
+            match self
+                .used
+                .compare_exchange_weak(used, end, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => return (self.memory.get() as *mut u8).add(start),
+                Err(actual) => used = actual,
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {}
+}
+
+#[global_allocator]
+static ARENA: Arena = Arena {
+    memory: UnsafeCell::new([0; ARENA_SIZE]),
+    used: AtomicUsize::new(0),
+};
+
+/// A patch from "hello" to "hello, world!\n", as written by bidiff
+#[rustfmt::skip]
+const PATCH: &[u8] = &[
+    0xDF, 0xB1, 0x00, 0x00, // magic
+    0x02, 0x10, 0x00, 0x00, // version
+    0, 0, 0, 0, 0, 0, 0, 0, // no maximum backreference distance
+    5, 0, 0, 0, 0, 0, // add "hello"
+    9, b',', b' ', b'w', b'o', b'r', b'l', b'd', b'!', b'\n', // copy
+    0, // seek
+];
+
+const OLDER: &[u8] = b"hello";
+
+fn main() -> Result<(), bipatch::DecodeError> {
+    let before = ARENA.used.load(Ordering::Relaxed);
+
+    let mut output = [0u8; 64];
+    let mut reader = bipatch::Reader::new(PATCH, Cursor::new(OLDER))?;
+    let mut len = 0;
+    loop {
+        let n = reader.read(&mut output[len..])?;
+        if n == 0 {
+            break;
+        }
+        len += n;
+    }
+    drop(reader);
+
+    let applying = ARENA.used.load(Ordering::Relaxed) - before;
+    assert_eq!(&output[..len], b"hello, world!\n");
+    print!("{}", String::from_utf8_lossy(&output[..len]));
+    println!(
+        "applied with {} bytes of heap ({} of {} used in total)",
+        applying,
+        ARENA.used.load(Ordering::Relaxed),
+        ARENA_SIZE
+    );
+    Ok(())
+}
@@ -0,0 +1,363 @@
+//! Reed–Solomon parity segments, for recovering patches with missing
+//! segments without retransmission (requires the `fec` feature).
+//!
+//! Data segments are grouped, in order, by `data_per_group`, and each group
+//! gets `parity_per_group` parity segments. Any `data_per_group` segments
+//! out of a group's data and parity segments are enough to recover it.
+//!
+//! Parity segments are numbered after the data segments (their `index` is
+//! at least `count`), so data segments are numbered exactly as they would
+//! be by [split_patch].
+
+use crate::segment::{insert_unique, split_patch, Segment, SegmentError};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use std::{collections::BTreeMap, convert::TryFrom};
+
+/// Size of the header at the start of each parity segment's data:
+/// data and parity segments per group as u16s, then the total patch length
+/// as a u64, all little-endian.
+pub const PARITY_HEADER_LEN: usize = 12;
+
+/// Split a patch like [split_patch], and append Reed–Solomon parity segments.
+///
+/// `data_per_group + parity_per_group` cannot exceed 256.
+///
+/// # Panics
+///
+/// Panics if `segment_size` or `data_per_group` is 0.
+pub fn split_patch_with_parity(
+    patch: &[u8],
+    segment_size: usize,
+    data_per_group: usize,
+    parity_per_group: usize,
+) -> Result<Vec<Segment>, SegmentError> {
+    assert!(data_per_group > 0, "data segments per group cannot be 0");
+    let mut segments = split_patch(patch, segment_size)?;
+    if parity_per_group == 0 {
+        return Ok(segments);
+    }
+    if data_per_group + parity_per_group > 256 {
+        return Err(SegmentError::InvalidParity);
+    }
+
+    let count = segments.len();
+    let groups = count.div_ceil(data_per_group);
+    if count + groups * parity_per_group > u32::MAX as usize {
+        return Err(SegmentError::TooManySegments);
+    }
+
+    let rs = ReedSolomon::new(data_per_group, parity_per_group)
+        .map_err(|_| SegmentError::InvalidParity)?;
+
+    let mut parity = Vec::with_capacity(groups * parity_per_group);
+    for (g, group) in segments.chunks(data_per_group).enumerate() {
+        // the last group may be short: pad it with empty shards
+        let mut shards: Vec<Vec<u8>> = (0..data_per_group + parity_per_group)
+            .map(|i| {
+                let mut shard = group.get(i).map(|s| s.data.clone()).unwrap_or_default();
+                shard.resize(segment_size, 0);
+                shard
+            })
+            .collect();
+        rs.encode(&mut shards)
+            .map_err(|_| SegmentError::InvalidParity)?;
+
+        for (j, shard) in shards.drain(data_per_group..).enumerate() {
+            let mut data = Vec::with_capacity(PARITY_HEADER_LEN + shard.len());
+            data.write_u16::<LittleEndian>(data_per_group as u16)
+                .unwrap();
+            data.write_u16::<LittleEndian>(parity_per_group as u16)
+                .unwrap();
+            data.write_u64::<LittleEndian>(patch.len() as u64).unwrap();
+            data.extend_from_slice(&shard);
+
+            let index = (count + g * parity_per_group + j) as u32;
+            parity.push(Segment::new(index, count as u32, data));
+        }
+    }
+
+    segments.append(&mut parity);
+    Ok(segments)
+}
+
+#[derive(PartialEq, Eq)]
+struct ParityParams {
+    data_per_group: usize,
+    parity_per_group: usize,
+    patch_len: u64,
+    segment_size: usize,
+}
+
+/// Read the parameters in a parity segment's header. They aren't trusted:
+/// the checksum only catches corruption in transit.
+fn parity_params(s: &Segment) -> Result<ParityParams, SegmentError> {
+    let mut header = s
+        .data
+        .get(..PARITY_HEADER_LEN)
+        .ok_or(SegmentError::Truncated)?;
+    let mut read_header = || -> std::io::Result<ParityParams> {
+        Ok(ParityParams {
+            data_per_group: header.read_u16::<LittleEndian>()? as usize,
+            parity_per_group: header.read_u16::<LittleEndian>()? as usize,
+            patch_len: header.read_u64::<LittleEndian>()?,
+            segment_size: s.data.len() - PARITY_HEADER_LEN,
+        })
+    };
+    let params = read_header().map_err(|_| SegmentError::Truncated)?;
+
+    let (k, m) = (params.data_per_group, params.parity_per_group);
+    if k == 0 || m == 0 || k + m > 256 {
+        return Err(SegmentError::InvalidParity);
+    }
+    Ok(params)
+}
+
+/// Reassemble a patch from data and parity segments, in any order,
+/// recovering missing data segments from parity segments when possible.
+///
+/// Segments come from the network, so when parity is needed, segment
+/// counts and parity headers are only trusted as far as the segments
+/// received can back them, and data segments longer than parity shards
+/// are rejected rather than cut short.
+pub fn reassemble_with_parity<'a, I>(segments: I) -> Result<Vec<u8>, SegmentError>
+where
+    I: IntoIterator<Item = &'a Segment>,
+{
+    let mut data: BTreeMap<u32, &Segment> = BTreeMap::new();
+    let mut parity: BTreeMap<u32, &Segment> = BTreeMap::new();
+    let mut count = None;
+
+    for s in segments {
+        s.verify()?;
+        let expected = *count.get_or_insert(s.count);
+        if s.count != expected {
+            return Err(SegmentError::WrongCount {
+                expected,
+                got: s.count,
+            });
+        }
+        if s.index < s.count {
+            insert_unique(&mut data, s)?;
+        } else {
+            insert_unique(&mut parity, s)?;
+        }
+    }
+
+    let count = count.unwrap_or(1);
+    let first_missing = (0..count).find(|i| !data.contains_key(i));
+    let first_missing = match first_missing {
+        None => return crate::segment::reassemble(data.values().copied()),
+        Some(index) => index,
+    };
+
+    let mut params = None;
+    for s in parity.values() {
+        let p = parity_params(s)?;
+        match &params {
+            Some(first) if *first != p => return Err(SegmentError::InvalidParity),
+            Some(_) => {}
+            None => params = Some(p),
+        }
+    }
+    let params = params.ok_or(SegmentError::Missing {
+        index: first_missing,
+    })?;
+
+    // data segments are all `segment_size` long, except maybe the last one.
+    // Recovering `count` of them takes at least as many segments, so the
+    // patch can't be longer than what was received could hold, whatever
+    // `count` and the header say.
+    let count = count as usize;
+    let segment_size = params.segment_size;
+    let received = data.len() + parity.len();
+    let patch_len = std::cmp::min(count, received)
+        .checked_mul(segment_size)
+        .and_then(|max_len| {
+            usize::try_from(params.patch_len)
+                .ok()
+                .filter(|&len| len <= max_len)
+        })
+        .ok_or(SegmentError::InvalidParity)?;
+
+    let (k, m) = (params.data_per_group, params.parity_per_group);
+    let rs = ReedSolomon::new(k, m).map_err(|_| SegmentError::InvalidParity)?;
+
+    let mut patch = Vec::new();
+    for g in 0..count.div_ceil(k) {
+        let first = g * k;
+        let group_len = std::cmp::min(k, count - first);
+
+        let mut shards = Vec::with_capacity(k + m);
+        for i in 0..k + m {
+            let shard = if i < k {
+                if i >= group_len {
+                    // padding shard, known to be all zeroes
+                    shards.push(Some(vec![0; segment_size]));
+                    continue;
+                }
+                match data.get(&((first + i) as u32)) {
+                    // a longer one would have to be cut short
+                    Some(s) if s.data.len() > segment_size => {
+                        return Err(SegmentError::InvalidParity)
+                    }
+                    Some(s) => {
+                        let mut shard = s.data.clone();
+                        shard.resize(segment_size, 0);
+                        Some(shard)
+                    }
+                    None => None,
+                }
+            } else {
+                let index = count + g * m + (i - k);
+                parity
+                    .get(&(index as u32))
+                    .map(|s| s.data[PARITY_HEADER_LEN..].to_vec())
+            };
+            shards.push(shard);
+        }
+
+        if shards[..group_len].iter().any(|s| s.is_none()) {
+            rs.reconstruct_data(&mut shards).map_err(|_| {
+                let index = (0..group_len).find(|&i| shards[i].is_none()).unwrap_or(0);
+                SegmentError::Missing {
+                    index: (first + index) as u32,
+                }
+            })?;
+        }
+
+        for shard in shards.into_iter().take(group_len) {
+            patch.extend_from_slice(&shard.expect("data shards are all present"));
+        }
+    }
+
+    // the last data segment may have been padded during reconstruction
+    if patch.len() < patch_len {
+        return Err(SegmentError::Truncated);
+    }
+    patch.truncate(patch_len);
+    Ok(patch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recover_missing_segments() {
+        let patch: Vec<u8> = (0..1000_u32).map(|i| (i * 13) as u8).collect();
+        // 16 data segments, in groups of 5 (the last one short), with 2
+        // parity segments per group
+        let segments = split_patch_with_parity(&patch, 64, 5, 2).unwrap();
+        assert_eq!(segments.len(), 16 + 4 * 2);
+
+        // lose two segments in the first group, and the last one
+        let received: Vec<&Segment> = segments
+            .iter()
+            .filter(|s| ![1, 3, 15].contains(&s.index))
+            .collect();
+        assert_eq!(reassemble_with_parity(received).unwrap(), patch);
+
+        // three losses in a group is more than parity can recover
+        let received: Vec<&Segment> = segments
+            .iter()
+            .filter(|s| ![5, 6, 7].contains(&s.index))
+            .collect();
+        assert!(matches!(
+            reassemble_with_parity(received),
+            Err(SegmentError::Missing { .. })
+        ));
+
+        // data segments alone still reassemble without parity
+        assert_eq!(reassemble_with_parity(&segments[..16]).unwrap(), patch);
+    }
+
+    #[test]
+    fn untrusted_parity_headers() {
+        let patch: Vec<u8> = (0..1000_u32).map(|i| (i * 7) as u8).collect();
+        let segments = split_patch_with_parity(&patch, 64, 5, 2).unwrap();
+
+        // lose a data segment, so parity is needed, and tamper with the
+        // parity segments' headers (with valid checksums)
+        let received = |tamper: &dyn Fn(&mut Segment)| {
+            let mut received: Vec<Segment> =
+                segments.iter().filter(|s| s.index != 2).cloned().collect();
+            for s in received.iter_mut().filter(|s| s.index == 16) {
+                tamper(s);
+                *s = Segment::new(s.index, s.count, s.data.clone());
+            }
+            reassemble_with_parity(&received)
+        };
+
+        assert_eq!(received(&|_| {}).unwrap(), patch);
+
+        // a patch longer than the data segments can hold
+        assert!(matches!(
+            received(&|s| s.data[4..12].copy_from_slice(&u64::MAX.to_le_bytes())),
+            Err(SegmentError::InvalidParity)
+        ));
+        // parameters that differ from the other parity segments'
+        assert!(matches!(
+            received(&|s| s.data[2..4].copy_from_slice(&3_u16.to_le_bytes())),
+            Err(SegmentError::InvalidParity)
+        ));
+        // a parity segment too short for its header
+        assert!(matches!(
+            received(&|s| s.data.truncate(PARITY_HEADER_LEN - 1)),
+            Err(SegmentError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn forged_segment_count() {
+        let patch: Vec<u8> = (0..1000_u32).map(|i| (i * 7) as u8).collect();
+        let segments = split_patch_with_parity(&patch, 64, 5, 2).unwrap();
+
+        // segments that claim a huge patch, and agree with each other: the
+        // patch length is capped by what they can hold, rather than
+        // allocated up front
+        let received: Vec<Segment> = segments
+            .iter()
+            .filter(|s| s.index != 2)
+            .map(|s| {
+                let mut s = s.clone();
+                s.count = u32::MAX - 8;
+                if s.index >= 16 {
+                    s.index += s.count - 16;
+                    s.data[4..12].copy_from_slice(&(1_u64 << 37).to_le_bytes());
+                }
+                s = Segment::new(s.index, s.count, s.data);
+                s
+            })
+            .collect();
+        assert!(matches!(
+            reassemble_with_parity(&received),
+            Err(SegmentError::InvalidParity)
+        ));
+    }
+
+    #[test]
+    fn overlong_data_segment() {
+        let patch: Vec<u8> = (0..1000_u32).map(|i| (i * 7) as u8).collect();
+        let segments = split_patch_with_parity(&patch, 64, 5, 2).unwrap();
+
+        // a data segment longer than the others, in a group that needs
+        // parity to recover another segment
+        let received: Vec<Segment> = segments
+            .iter()
+            .filter(|s| s.index != 2)
+            .map(|s| {
+                let mut s = s.clone();
+                if s.index == 1 {
+                    s.data.push(0);
+                    s = Segment::new(s.index, s.count, s.data);
+                }
+                s
+            })
+            .collect();
+        assert_eq!(
+            reassemble_with_parity(&received),
+            Err(SegmentError::InvalidParity)
+        );
+    }
+}
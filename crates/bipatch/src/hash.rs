@@ -0,0 +1,138 @@
+//! Hashing patched output while it's written, so it can be verified
+//! without reading it back (requires the `sha2` and/or `blake3` feature).
+
+use crate::{multi_writer::MultiWriter, ApplyReport, DecodeError};
+use std::io::{self, Read, Seek, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    #[cfg(feature = "sha2")]
+    Sha256,
+    #[cfg(feature = "blake3")]
+    Blake3,
+}
+
+enum State {
+    #[cfg(feature = "sha2")]
+    Sha256(sha2::Sha256),
+    #[cfg(feature = "blake3")]
+    Blake3(Box<blake3::Hasher>),
+}
+
+/// Hashes everything written to it
+pub struct Hasher {
+    state: State,
+}
+
+impl Hasher {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        let state = match algorithm {
+            #[cfg(feature = "sha2")]
+            HashAlgorithm::Sha256 => State::Sha256(Default::default()),
+            #[cfg(feature = "blake3")]
+            HashAlgorithm::Blake3 => State::Blake3(Default::default()),
+        };
+        Self { state }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match &mut self.state {
+            #[cfg(feature = "sha2")]
+            State::Sha256(h) => sha2::Digest::update(h, data),
+            #[cfg(feature = "blake3")]
+            State::Blake3(h) => {
+                h.update(data);
+            }
+        }
+    }
+
+    pub fn finalize(self) -> Vec<u8> {
+        match self.state {
+            #[cfg(feature = "sha2")]
+            State::Sha256(h) => sha2::Digest::finalize(h).to_vec(),
+            #[cfg(feature = "blake3")]
+            State::Blake3(h) => h.finalize().as_bytes().to_vec(),
+        }
+    }
+}
+
+impl Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Like [apply](crate::apply), but also returns the hash of the output
+pub fn apply_hashed<R, RS, W>(
+    patch: R,
+    old: RS,
+    out: &mut W,
+    algorithm: HashAlgorithm,
+) -> Result<(ApplyReport, Vec<u8>), DecodeError>
+where
+    R: Read,
+    RS: Read + Seek,
+    W: Write + ?Sized,
+{
+    let mut hasher = Hasher::new(algorithm);
+    // `&mut W` is sized even when `W` isn't (a `dyn Write`, say)
+    let mut out = out;
+    let report = crate::apply(
+        patch,
+        old,
+        &mut MultiWriter::new(vec![&mut out, &mut hasher]),
+    )?;
+    Ok((report, hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use integer_encoding::VarIntWriter;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn hashes_output() {
+        // a patch that copies "abc" without touching the older file
+        let mut patch = crate::tests::header();
+        patch.write_varint(0_u64).unwrap();
+        patch.write_varint(3_u64).unwrap();
+        patch.extend_from_slice(b"abc");
+        patch.write_varint(0_i64).unwrap();
+
+        let expected: &[(HashAlgorithm, &str)] = &[
+            #[cfg(feature = "sha2")]
+            (
+                HashAlgorithm::Sha256,
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            ),
+            #[cfg(feature = "blake3")]
+            (
+                HashAlgorithm::Blake3,
+                "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85",
+            ),
+        ];
+        for &(algorithm, digest) in expected {
+            let mut out = Vec::new();
+            let (report, hash) =
+                apply_hashed(&patch[..], io::Cursor::new(&[][..]), &mut out, algorithm).unwrap();
+            assert_eq!(out, b"abc");
+            assert_eq!(report.bytes_written, 3);
+            assert_eq!(hex(&hash), digest);
+
+            // writers behind a `dyn Write` work too
+            let out: &mut dyn Write = &mut io::sink();
+            let (_, hash) =
+                apply_hashed(&patch[..], io::Cursor::new(&[][..]), out, algorithm).unwrap();
+            assert_eq!(hex(&hash), digest);
+        }
+    }
+}
@@ -1,20 +1,91 @@
 use byteorder::{LittleEndian, ReadBytesExt};
-use integer_encoding::VarIntReader;
+use integer_encoding::{VarInt, VarIntReader};
 use std::{
     cmp::min,
+    convert::TryFrom,
     error::Error as StdError,
     fmt,
-    io::{self, ErrorKind, Read, Seek, SeekFrom},
+    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
+    time::{Duration, Instant},
 };
 
+pub mod multi_writer;
+pub mod retarget;
+pub mod scatter;
+pub mod segment;
+pub mod spool;
+pub mod throttle;
+
+#[cfg(feature = "fec")]
+pub mod fec;
+
+#[cfg(any(feature = "sha2", feature = "blake3"))]
+pub mod hash;
+
 pub const MAGIC: u32 = 0xB1DF;
-pub const VERSION: u32 = 0x1000;
+/// The latest version of the format, which [Reader] reads along with the
+/// ones in [SUPPORTED_VERSIONS]
+pub const VERSION: u32 = 0x1002;
+
+/// Every version [Reader] can read:
+///
+/// - 0x1000: magic, version, then controls
+/// - 0x1001: magic, version, the patch's maximum backreference distance
+///   (see [Reader::max_backref_distance]), then controls
+/// - 0x1002: like 0x1001, but the seek at the end of each control can be
+///   absolute, see [ControlSeek]
+pub const SUPPORTED_VERSIONS: &[u32] = &[0x1000, 0x1001, 0x1002];
+
+/// Where a control leaves the older file
+///
+/// Up to version 0x1001, controls end with a relative seek, as a zigzag
+/// varint. From 0x1002 on, they end with a varint whose lowest bit tells
+/// which kind of seek the rest holds: a relative seek (0), zigzag encoded,
+/// or an absolute position in the older file (1). Encoders use whichever
+/// is shorter, so long seeks back and forth across the older file take
+/// fewer bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlSeek {
+    Relative(i64),
+    Absolute(u64),
+}
+
+impl ControlSeek {
+    /// The shortest encoding of a seek by `seek` bytes from `pos`, for
+    /// version 0x1002 and later
+    pub fn encode(pos: u64, seek: i64) -> Option<u64> {
+        let relative = ((seek << 1) ^ (seek >> 63)) as u64;
+        let relative = relative.checked_mul(2);
+        let absolute = checked_seek(pos, seek)
+            .ok()
+            .and_then(|p| p.checked_mul(2))
+            .map(|p| p | 1);
+        match (relative, absolute) {
+            (Some(r), Some(a)) if a.required_space() < r.required_space() => Some(a),
+            (Some(r), _) => Some(r),
+            (None, a) => a,
+        }
+    }
+
+    /// Decode a seek encoded for version 0x1002 and later
+    pub fn decode(x: u64) -> Self {
+        let v = x >> 1;
+        if x & 1 == 0 {
+            ControlSeek::Relative((v >> 1) as i64 ^ -((v & 1) as i64))
+        } else {
+            ControlSeek::Absolute(v)
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum DecodeError {
     IO(io::Error),
     WrongMagic(u32),
     WrongVersion(u32),
+    LengthOverflow(u64),
+    InvalidSeek { pos: u64, seek: i64 },
+    InvalidPosition(u64),
 }
 
 impl fmt::Display for DecodeError {
@@ -25,7 +96,20 @@ impl fmt::Display for DecodeError {
                 write!(f, "wrong magic: expected `{:X}`, got `{:X}`", MAGIC, e)
             }
             DecodeError::WrongVersion(e) => {
-                write!(f, "wrong version: expected `{:X}`, got `{:X}`", VERSION, e)
+                write!(
+                    f,
+                    "wrong version: expected one of `{:X?}`, got `{:X}`",
+                    SUPPORTED_VERSIONS, e
+                )
+            }
+            DecodeError::LengthOverflow(len) => {
+                write!(f, "length {} does not fit in this platform's usize", len)
+            }
+            DecodeError::InvalidSeek { pos, seek } => {
+                write!(f, "invalid seek: {} from old file position {}", seek, pos)
+            }
+            DecodeError::InvalidPosition(pos) => {
+                write!(f, "invalid old file position: {}", pos)
             }
         }
     }
@@ -37,6 +121,9 @@ impl StdError for DecodeError {
             DecodeError::IO(e) => Some(e),
             DecodeError::WrongMagic { .. } => None,
             DecodeError::WrongVersion { .. } => None,
+            DecodeError::LengthOverflow { .. } => None,
+            DecodeError::InvalidSeek { .. } => None,
+            DecodeError::InvalidPosition { .. } => None,
         }
     }
 }
@@ -54,6 +141,10 @@ where
 {
     patch: R,
     old: RS,
+    old_pos: u64,
+    old_read: u64,
+    version: u32,
+    max_backref_distance: Option<u64>,
     state: ReaderState,
     buf: Vec<u8>,
 }
@@ -72,23 +163,174 @@ where
     RS: Read + Seek,
 {
     pub fn new(mut patch: R, old: RS) -> Result<Self, DecodeError> {
-        let magic = patch.read_u32::<LittleEndian>()?;
-        if magic != MAGIC {
-            return Err(DecodeError::WrongMagic(magic));
-        }
-
-        let version = patch.read_u32::<LittleEndian>()?;
-        if version != VERSION {
-            return Err(DecodeError::WrongMagic(version));
-        }
+        let (version, max_backref_distance) = read_header(&mut patch)?;
 
         Ok(Self {
             patch,
             old,
+            old_pos: 0,
+            old_read: 0,
+            version,
+            max_backref_distance,
             state: ReaderState::Initial,
             buf: vec![0u8; 4096],
         })
     }
+
+    /// Number of bytes read from the older file so far
+    pub fn old_bytes_read(&self) -> u64 {
+        self.old_read
+    }
+
+    /// How far before (or after) the furthest position read in the older
+    /// file the patch may start reading it again, if the patch records a
+    /// limit. Appliers that only keep that many bytes of the older file
+    /// around can apply the patch.
+    pub fn max_backref_distance(&self) -> Option<u64> {
+        self.max_backref_distance
+    }
+
+    /// Size of the internal buffer used to read ADD data from the patch
+    pub fn buffer_size(&self) -> usize {
+        self.buf.len()
+    }
+}
+
+/// Statistics about applying a patch, returned by [apply]
+#[derive(Debug, Clone, Default)]
+pub struct ApplyReport {
+    /// Number of bytes read from the older file
+    pub old_bytes_read: u64,
+    /// Number of bytes written to the output
+    pub bytes_written: u64,
+    /// Combined size of the buffers allocated for applying: the
+    /// [Reader]'s and the one output is copied through. This is their
+    /// configured size, not a measurement; they're fixed, whatever the
+    /// size of the inputs.
+    pub buffer_size: usize,
+    /// Time taken to apply the patch, including reading the older file
+    /// and writing the output
+    pub duration: Duration,
+}
+
+/// Size of the buffer [apply] copies through
+const APPLY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Apply `patch` to `old`, writing the newer file to `out`
+pub fn apply<R, RS, W>(patch: R, old: RS, out: &mut W) -> Result<ApplyReport, DecodeError>
+where
+    R: Read,
+    RS: Read + Seek,
+    W: Write + ?Sized,
+{
+    apply_with_progress(patch, old, out, None, |_, _| {})
+}
+
+/// Like [apply], but calls `on_progress` with the number of bytes written
+/// so far and `total` after each write to `out`.
+///
+/// Patches don't record the size of the newer file, so `total` is only
+/// passed along for convenience, for callers who know it from elsewhere
+/// (like an update manifest).
+pub fn apply_with_progress<R, RS, W, F>(
+    patch: R,
+    old: RS,
+    out: &mut W,
+    total: Option<u64>,
+    mut on_progress: F,
+) -> Result<ApplyReport, DecodeError>
+where
+    R: Read,
+    RS: Read + Seek,
+    W: Write + ?Sized,
+    F: FnMut(u64, Option<u64>),
+{
+    let start = Instant::now();
+    let mut r = Reader::new(patch, old)?;
+    let mut buf = vec![0u8; APPLY_BUFFER_SIZE];
+    let mut bytes_written = 0;
+
+    loop {
+        let n = match r.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        out.write_all(&buf[..n])?;
+        bytes_written += n as u64;
+        on_progress(bytes_written, total);
+    }
+
+    Ok(ApplyReport {
+        old_bytes_read: r.old_bytes_read(),
+        bytes_written,
+        buffer_size: r.buffer_size() + buf.len(),
+        duration: start.elapsed(),
+    })
+}
+
+/// Reads a patch's header, up to its first control, and returns its
+/// version and maximum backreference distance, if it has one.
+fn read_header<R: Read>(patch: &mut R) -> Result<(u32, Option<u64>), DecodeError> {
+    let magic = patch.read_u32::<LittleEndian>()?;
+    if magic != MAGIC {
+        return Err(DecodeError::WrongMagic(magic));
+    }
+
+    let version = patch.read_u32::<LittleEndian>()?;
+    if !SUPPORTED_VERSIONS.contains(&version) {
+        return Err(DecodeError::WrongVersion(version));
+    }
+
+    let max_backref_distance = if version >= 0x1001 {
+        Some(patch.read_u64::<LittleEndian>()?).filter(|&d| d != 0)
+    } else {
+        None
+    };
+    Ok((version, max_backref_distance))
+}
+
+/// Reads the seek that ends a control, and returns the position in the
+/// old file it leads to from `pos`, refusing positions that can't be
+/// reached with a relative seek either.
+fn read_seek<R: Read>(r: &mut R, version: u32, pos: u64) -> io::Result<u64> {
+    let seek = if version >= 0x1002 {
+        ControlSeek::decode(r.read_varint()?)
+    } else {
+        ControlSeek::Relative(r.read_varint()?)
+    };
+    match seek {
+        ControlSeek::Relative(seek) => checked_seek(pos, seek),
+        ControlSeek::Absolute(p) if p <= i64::MAX as u64 => Ok(p),
+        ControlSeek::Absolute(p) => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            DecodeError::InvalidPosition(p),
+        )),
+    }
+}
+
+/// Reads a length, which is always encoded as a u64 varint, and
+/// converts it to a `usize` without truncating on 32-bit platforms.
+fn read_len<R: Read>(r: &mut R) -> io::Result<usize> {
+    let len: u64 = r.read_varint()?;
+    usize::try_from(len)
+        .map_err(|_| io::Error::new(ErrorKind::InvalidData, DecodeError::LengthOverflow(len)))
+}
+
+/// Applies a relative seek to a position in the old file, refusing
+/// to go before its start instead of wrapping around.
+fn checked_seek(pos: u64, seek: i64) -> io::Result<u64> {
+    i64::try_from(pos)
+        .ok()
+        .and_then(|p| p.checked_add(seek))
+        .and_then(|p| u64::try_from(p).ok())
+        .ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                DecodeError::InvalidSeek { pos, seek },
+            )
+        })
 }
 
 impl<R, RS> Read for Reader<R, RS>
@@ -101,7 +343,7 @@ where
 
         while !buf.is_empty() {
             let processed = match self.state {
-                ReaderState::Initial => match self.patch.read_varint() {
+                ReaderState::Initial => match read_len(&mut self.patch) {
                     Ok(add_len) => {
                         self.state = ReaderState::Add(add_len);
                         0
@@ -121,6 +363,8 @@ where
 
                     let out = &mut buf[..n];
                     self.old.read_exact(out)?;
+                    self.old_pos += n as u64;
+                    self.old_read += n as u64;
 
                     let dif = &mut self.buf[..n];
                     self.patch.read_exact(dif)?;
@@ -130,7 +374,7 @@ where
                     }
 
                     if add_len == n {
-                        let copy_len: usize = self.patch.read_varint()?;
+                        let copy_len = read_len(&mut self.patch)?;
                         self.state = ReaderState::Copy(copy_len)
                     } else {
                         self.state = ReaderState::Add(add_len - n);
@@ -145,8 +389,13 @@ where
                     self.patch.read_exact(out)?;
 
                     if copy_len == n {
-                        let seek: i64 = self.patch.read_varint()?;
+                        let old_pos = read_seek(&mut self.patch, self.version, self.old_pos)?;
+                        // relative to where `old` is, which may not have
+                        // started at 0
+                        let seek = old_pos as i64 - self.old_pos as i64;
                         self.old.seek(SeekFrom::Current(seek))?;
+                        // only once the old file is actually there
+                        self.old_pos = old_pos;
                         self.state = ReaderState::Initial;
                     } else {
                         self.state = ReaderState::Copy(copy_len - n);
@@ -165,3 +414,195 @@ where
         Ok(read)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use integer_encoding::VarIntWriter;
+
+    /// The header of a patch in the latest version, with no backreference
+    /// distance
+    pub(crate) fn header() -> Vec<u8> {
+        let mut patch = Vec::new();
+        patch.extend_from_slice(&MAGIC.to_le_bytes());
+        patch.extend_from_slice(&VERSION.to_le_bytes());
+        patch.extend_from_slice(&0_u64.to_le_bytes());
+        patch
+    }
+
+    /// Write a relative seek, the way the latest version encodes them
+    pub(crate) fn write_seek(patch: &mut Vec<u8>, seek: i64) {
+        let zigzag = ((seek << 1) ^ (seek >> 63)) as u64;
+        patch.write_varint(zigzag << 1).unwrap();
+    }
+
+    #[test]
+    fn lengths_are_u64_varints() {
+        let mut buf = Vec::new();
+        buf.write_varint(0x1234_5678_u64).unwrap();
+        assert_eq!(read_len(&mut &buf[..]).unwrap(), 0x1234_5678);
+    }
+
+    #[test]
+    fn wrong_header_is_rejected() {
+        let old = || io::Cursor::new(Vec::new());
+
+        let mut patch = Vec::new();
+        patch.extend_from_slice(&0xDEAD_u32.to_le_bytes());
+        patch.extend_from_slice(&VERSION.to_le_bytes());
+        assert!(matches!(
+            Reader::new(&patch[..], old()),
+            Err(DecodeError::WrongMagic(0xDEAD))
+        ));
+
+        let mut patch = Vec::new();
+        patch.extend_from_slice(&MAGIC.to_le_bytes());
+        patch.extend_from_slice(&0x2000_u32.to_le_bytes());
+        assert!(matches!(
+            Reader::new(&patch[..], old()),
+            Err(DecodeError::WrongVersion(0x2000))
+        ));
+    }
+
+    #[test]
+    fn reads_every_version() {
+        // ADD 2 bytes, COPY "!", seek back to the start, then ADD 1 byte
+        let controls = |seek: &[u8]| {
+            let mut controls = Vec::new();
+            controls.write_varint(2_u64).unwrap();
+            controls.extend_from_slice(&[0, 1]);
+            controls.write_varint(1_u64).unwrap();
+            controls.push(b'!');
+            controls.extend_from_slice(seek);
+            controls.extend_from_slice(&[1, 0, 0, 0]);
+            controls
+        };
+        // -2 zigzag encoded, then shifted for the seek kind
+        let (relative, relative_1002, absolute_1002) = (&[3][..], &[6][..], &[1][..]);
+
+        let no_limit = &[0; 8][..];
+        for &(version, fields, seek, max_backref_distance) in &[
+            (0x1000_u32, &[][..], relative, None),
+            (0x1001, no_limit, relative, None),
+            (0x1001, &[16, 0, 0, 0, 0, 0, 0, 0], relative, Some(16_u64)),
+            (0x1002, no_limit, relative_1002, None),
+            (0x1002, no_limit, absolute_1002, None),
+        ] {
+            let mut patch = Vec::new();
+            patch.extend_from_slice(&MAGIC.to_le_bytes());
+            patch.extend_from_slice(&version.to_le_bytes());
+            patch.extend_from_slice(fields);
+            patch.extend_from_slice(&controls(seek));
+
+            let mut r = Reader::new(&patch[..], io::Cursor::new(b"ab")).unwrap();
+            assert_eq!(r.max_backref_distance(), max_backref_distance);
+            let mut fresh = Vec::new();
+            r.read_to_end(&mut fresh).unwrap();
+            assert_eq!(fresh, b"ac!a", "version {:X}", version);
+        }
+    }
+
+    #[test]
+    fn control_seeks() {
+        for &(pos, seek) in &[
+            (0, 0),
+            (10, -10),
+            (1 << 40, 3),
+            (1 << 40, -(1 << 40)),
+            (5, 1 << 50),
+        ] {
+            let x = ControlSeek::encode(pos, seek).unwrap();
+            let to = match ControlSeek::decode(x) {
+                ControlSeek::Relative(seek) => (pos as i64 + seek) as u64,
+                ControlSeek::Absolute(to) => to,
+            };
+            assert_eq!(to as i64, pos as i64 + seek);
+        }
+        // seeking back to the start of the older file from far away is
+        // shorter as an absolute position
+        assert_eq!(ControlSeek::encode(1 << 40, -(1 << 40)), Some(1));
+        assert_eq!(ControlSeek::decode(1), ControlSeek::Absolute(0));
+        // before the start, only a relative seek can express it (and the
+        // applier rejects it)
+        assert_eq!(
+            ControlSeek::decode(ControlSeek::encode(1, -2).unwrap()),
+            ControlSeek::Relative(-2)
+        );
+    }
+
+    #[test]
+    fn seek_before_start_is_rejected() {
+        let mut patch = header();
+        // ADD 1 byte, COPY nothing, then seek back 2
+        patch.write_varint(1_u64).unwrap();
+        patch.push(0);
+        patch.write_varint(0_u64).unwrap();
+        write_seek(&mut patch, -2);
+
+        let old = io::Cursor::new(vec![42_u8; 4]);
+        let mut r = Reader::new(&patch[..], old).unwrap();
+        let err = io::copy(&mut r, &mut io::sink()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        let inner = err.into_inner().unwrap().downcast::<DecodeError>().unwrap();
+        assert!(matches!(
+            *inner,
+            DecodeError::InvalidSeek { pos: 1, seek: -2 }
+        ));
+    }
+
+    #[test]
+    fn apply_report() {
+        let mut patch = header();
+        // ADD 3 bytes, COPY 2 bytes, seek back to the start, ADD 1 byte
+        patch.write_varint(3_u64).unwrap();
+        patch.extend_from_slice(&[0, 1, 2]);
+        patch.write_varint(2_u64).unwrap();
+        patch.extend_from_slice(b"yz");
+        write_seek(&mut patch, -3);
+        patch.write_varint(1_u64).unwrap();
+        patch.push(0);
+        patch.write_varint(0_u64).unwrap();
+        write_seek(&mut patch, 0);
+
+        let mut out = Vec::new();
+        let report = apply(&patch[..], io::Cursor::new(b"abc"), &mut out).unwrap();
+        assert_eq!(out, b"aceyza");
+        assert_eq!(report.old_bytes_read, 4);
+        assert_eq!(report.bytes_written, 6);
+    }
+
+    #[test]
+    fn apply_progress() {
+        let mut patch = header();
+        // COPY more than one buffer's worth
+        let len = APPLY_BUFFER_SIZE as u64 * 2 + 10;
+        patch.write_varint(0_u64).unwrap();
+        patch.write_varint(len).unwrap();
+        patch.resize(patch.len() + len as usize, 7);
+        write_seek(&mut patch, 0);
+
+        let mut progress = Vec::new();
+        let report = apply_with_progress(
+            &patch[..],
+            io::Cursor::new(b""),
+            &mut io::sink(),
+            Some(len),
+            |written, total| progress.push((written, total)),
+        )
+        .unwrap();
+
+        assert!(progress.len() >= 3);
+        assert!(progress.windows(2).all(|w| w[0].0 < w[1].0));
+        assert_eq!(progress.last(), Some(&(len, Some(len))));
+        assert_eq!(report.bytes_written, len);
+    }
+
+    #[cfg(target_pointer_width = "32")]
+    #[test]
+    fn oversized_length_is_rejected() {
+        let mut buf = Vec::new();
+        buf.write_varint(u64::from(u32::MAX) + 1).unwrap();
+        let err = read_len(&mut &buf[..]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
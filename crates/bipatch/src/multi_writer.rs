@@ -0,0 +1,59 @@
+//! Writing patched output to several sinks at once.
+//!
+//! ```no_run
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let (patch, old) = (std::io::empty(), std::io::Cursor::new(vec![]));
+//! # let (mut partition, mut verifier) = (std::io::sink(), std::io::sink());
+//! use bipatch::multi_writer::MultiWriter;
+//!
+//! let mut out = MultiWriter::new(vec![&mut partition, &mut verifier]);
+//! bipatch::apply(patch, old, &mut out)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::io::{self, Write};
+
+/// Writes everything to each of its sinks, in order
+pub struct MultiWriter<'a> {
+    sinks: Vec<&'a mut dyn Write>,
+}
+
+impl<'a> MultiWriter<'a> {
+    pub fn new(sinks: Vec<&'a mut dyn Write>) -> Self {
+        Self { sinks }
+    }
+}
+
+impl<'a> Write for MultiWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // partial writes would leave sinks out of step, so write it all
+        for sink in self.sinks.iter_mut() {
+            sink.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for sink in self.sinks.iter_mut() {
+            sink.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_to_all_sinks() {
+        let (mut a, mut b) = (Vec::new(), Vec::new());
+        let mut w = MultiWriter::new(vec![&mut a, &mut b]);
+        w.write_all(b"hello ").unwrap();
+        w.write_all(b"world").unwrap();
+        w.flush().unwrap();
+        assert_eq!(a, b"hello world");
+        assert_eq!(b, b"hello world");
+    }
+}
@@ -0,0 +1,228 @@
+//! Rebasing a patch onto an older file that differs a little from the one
+//! it was made for.
+//!
+//! Installed bases sometimes drift from the file a patch was made against
+//! (a few configuration blocks edited in place, say). When the drifted
+//! file is at hand, along with the one the patch expects, [retarget]
+//! rewrites the patch for it, so a single patch can serve both:
+//!
+//! ```no_run
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let (patch, expected, installed) = (Vec::new(), Vec::new(), Vec::new());
+//! let patch = bipatch::retarget::retarget(&patch, &expected, &installed)?;
+//! let mut fresh = Vec::new();
+//! bipatch::apply(&patch[..], std::io::Cursor::new(&installed), &mut fresh)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{read_header, read_len, read_seek, ControlSeek, DecodeError, MAGIC, VERSION};
+use byteorder::{LittleEndian, WriteBytesExt};
+use integer_encoding::VarIntWriter;
+use std::{
+    convert::TryFrom,
+    io::{self, ErrorKind},
+};
+
+/// Rewrite `patch`, made against `old_expected`, so that it produces the
+/// same output when applied to `old_actual`.
+///
+/// Parts of adds that read bytes the two older files have in common are
+/// kept. Everywhere else (bytes that differ, or that `old_actual` is too
+/// short to have) the output is stored as new data instead, and the add
+/// resumes after it. Patches stay close to their original size when the
+/// files only differ in a few places, and grow by up to the size of the
+/// output otherwise.
+pub fn retarget(
+    patch: &[u8],
+    old_expected: &[u8],
+    old_actual: &[u8],
+) -> Result<Vec<u8>, DecodeError> {
+    let mut r = patch;
+    let (version, max_backref_distance) = read_header(&mut r)?;
+
+    // reading less of the older file than the patch did keeps it within
+    // any backreference distance it had
+    let mut out = Vec::with_capacity(patch.len());
+    out.write_u32::<LittleEndian>(MAGIC)?;
+    out.write_u32::<LittleEndian>(VERSION)?;
+    out.write_u64::<LittleEndian>(max_backref_distance.unwrap_or(0))?;
+    let mut w = ControlWriter {
+        out,
+        add: Vec::new(),
+        copy: Vec::new(),
+        seek: 0,
+        pos: 0,
+    };
+
+    let mut pos = 0_u64;
+    while !r.is_empty() {
+        let len = read_len(&mut r)?;
+        let add = take(&mut r, len)?;
+        let start = usize::try_from(pos).unwrap_or(usize::MAX);
+        let expected = start
+            .checked_add(add.len())
+            .and_then(|end| old_expected.get(start..end))
+            .ok_or_else(|| invalid("add reads past the end of the expected older file"))?;
+        for (i, (&d, &e)) in add.iter().zip(expected).enumerate() {
+            if old_actual.get(start + i) == Some(&e) {
+                w.add(d)?;
+            } else {
+                w.copy(&[e.wrapping_add(d)]);
+                w.seek(1);
+            }
+        }
+        pos += add.len() as u64;
+
+        let len = read_len(&mut r)?;
+        let copy = take(&mut r, len)?;
+        w.copy(copy);
+
+        let next = read_seek(&mut r, version, pos)?;
+        w.seek(next as i64 - pos as i64);
+        pos = next;
+    }
+
+    Ok(w.finish()?)
+}
+
+/// Split `len` bytes off the front of `r`
+fn take<'a>(r: &mut &'a [u8], len: usize) -> io::Result<&'a [u8]> {
+    if len > r.len() {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    let (head, tail) = r.split_at(len);
+    *r = tail;
+    Ok(head)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// Builds controls from adds, copies and seeks in any order
+struct ControlWriter {
+    out: Vec<u8>,
+    add: Vec<u8>,
+    copy: Vec<u8>,
+    seek: i64,
+    /// position in the older file where `add` starts
+    pos: u64,
+}
+
+impl ControlWriter {
+    fn add(&mut self, byte: u8) -> io::Result<()> {
+        if !self.copy.is_empty() || self.seek != 0 {
+            self.flush()?;
+        }
+        self.add.push(byte);
+        Ok(())
+    }
+
+    /// Copies don't read the older file, so they can go before a pending
+    /// seek
+    fn copy(&mut self, data: &[u8]) {
+        self.copy.extend_from_slice(data);
+    }
+
+    fn seek(&mut self, seek: i64) {
+        self.seek += seek;
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let out = &mut self.out;
+        out.write_varint(self.add.len() as u64)?;
+        out.extend_from_slice(&self.add);
+        out.write_varint(self.copy.len() as u64)?;
+        out.extend_from_slice(&self.copy);
+        let from = self.pos + self.add.len() as u64;
+        let seek = ControlSeek::encode(from, self.seek)
+            .ok_or_else(|| invalid("seek out of the older file's range"))?;
+        out.write_varint(seek)?;
+
+        self.pos = (from as i64 + self.seek) as u64;
+        self.add.clear();
+        self.copy.clear();
+        self.seek = 0;
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<Vec<u8>> {
+        if !self.add.is_empty() || !self.copy.is_empty() || self.seek != 0 {
+            self.flush()?;
+        }
+        Ok(self.out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::write_seek;
+    use std::io::Cursor;
+
+    fn apply(patch: &[u8], older: &[u8]) -> Vec<u8> {
+        let mut fresh = Vec::new();
+        crate::apply(patch, Cursor::new(older), &mut fresh).unwrap();
+        fresh
+    }
+
+    #[test]
+    fn retarget_onto_drifted_base() {
+        let expected = b"name=device\nmode=fast\nlevel=3\ntrailer".to_vec();
+        // ADD "name=device\n" (unchanged), COPY "x", seek past "mode=fast\n",
+        // ADD "level=3\n" (as "level=4\n"), then seek back to the start and
+        // ADD "name"
+        let mut patch = crate::tests::header();
+        patch.write_varint(12_u64).unwrap();
+        patch.extend_from_slice(&[0; 12]);
+        patch.write_varint(1_u64).unwrap();
+        patch.push(b'x');
+        write_seek(&mut patch, 10);
+        patch.write_varint(8_u64).unwrap();
+        patch.extend_from_slice(&[0, 0, 0, 0, 0, 0, 1, 0]);
+        patch.write_varint(0_u64).unwrap();
+        write_seek(&mut patch, -30);
+        patch.write_varint(4_u64).unwrap();
+        patch.extend_from_slice(&[0; 4]);
+        patch.write_varint(0_u64).unwrap();
+        write_seek(&mut patch, 0);
+        let newer = apply(&patch, &expected);
+        assert_eq!(newer, b"name=device\nxlevel=4\nname");
+
+        // onto the same file, the patch doesn't change
+        let same = retarget(&patch, &expected, &expected).unwrap();
+        assert_eq!(same, patch);
+
+        for actual in [
+            // an edited value, in a part the patch reads and one it skips
+            &b"name=device\nmode=slow\nlevel=5\ntrailer"[..],
+            // a different length, which moves everything after it
+            &b"name=device\nmode=fast\nlevel=33\ntrailer"[..],
+            // too short for the patch
+            &b"name=dev"[..],
+            &b""[..],
+        ] {
+            let retargeted = retarget(&patch, &expected, actual).unwrap();
+            assert_eq!(apply(&retargeted, actual), newer, "{:?}", actual);
+        }
+    }
+
+    #[test]
+    fn retarget_rejects_invalid_patches() {
+        let mut patch = crate::tests::header();
+        // ADD more than the expected older file has
+        patch.write_varint(8_u64).unwrap();
+        patch.extend_from_slice(&[0; 8]);
+        patch.write_varint(0_u64).unwrap();
+        write_seek(&mut patch, 0);
+        assert!(retarget(&patch, b"abc", b"abc").is_err());
+
+        // a truncated copy
+        let mut patch = crate::tests::header();
+        patch.write_varint(0_u64).unwrap();
+        patch.write_varint(100_u64).unwrap();
+        patch.extend_from_slice(b"short");
+        assert!(retarget(&patch, b"abc", b"abc").is_err());
+    }
+}
@@ -0,0 +1,115 @@
+//! Applying patches into several non-contiguous output buffers, for
+//! example when the newer image is split across flash partitions.
+//!
+//! A [ScatterWriter] maps the logical offsets of the newer file onto a
+//! list of regions, and can be passed to [apply](crate::apply) like any
+//! other writer.
+
+use std::io::{self, ErrorKind, Write};
+
+/// Writes a contiguous stream into a list of regions, each covering
+/// a range of logical offsets.
+pub struct ScatterWriter<'a> {
+    /// Regions sorted by offset, with their logical offsets
+    regions: Vec<(u64, &'a mut [u8])>,
+    /// Index of the region containing `pos`, or the next one
+    current: usize,
+    pos: u64,
+}
+
+impl<'a> ScatterWriter<'a> {
+    /// Create a writer from `(logical offset, buffer)` pairs, in any order.
+    /// Regions may leave gaps between them, but cannot overlap.
+    pub fn new(mut regions: Vec<(u64, &'a mut [u8])>) -> io::Result<Self> {
+        regions.sort_by_key(|(offset, _)| *offset);
+        for pair in regions.windows(2) {
+            let (offset, buf) = (&pair[0].0, &pair[0].1);
+            let end = offset.checked_add(buf.len() as u64);
+            if end.is_none_or(|end| end > pair[1].0) {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("output region at offset {} overlaps the next one", offset),
+                ));
+            }
+        }
+
+        Ok(Self {
+            regions,
+            current: 0,
+            pos: 0,
+        })
+    }
+
+    /// Logical offset of the next byte to be written
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+}
+
+impl<'a> Write for ScatterWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        // skip regions entirely behind us
+        while let Some((offset, region)) = self.regions.get(self.current) {
+            if offset + region.len() as u64 > self.pos {
+                break;
+            }
+            self.current += 1;
+        }
+
+        let (offset, region) = match self.regions.get_mut(self.current) {
+            Some((offset, region)) if *offset <= self.pos => (*offset, region),
+            _ => {
+                return Err(io::Error::new(
+                    ErrorKind::WriteZero,
+                    format!("no output region at offset {}", self.pos),
+                ))
+            }
+        };
+
+        let start = (self.pos - offset) as usize;
+        let n = std::cmp::min(buf.len(), region.len() - start);
+        region[start..start + n].copy_from_slice(&buf[..n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scatter() {
+        let mut a = [0u8; 4];
+        let mut b = [0u8; 6];
+        let mut w = ScatterWriter::new(vec![(4, &mut b[..]), (0, &mut a[..])]).unwrap();
+        w.write_all(b"0123456789").unwrap();
+        assert_eq!(w.position(), 10);
+        assert!(w.write_all(b"x").is_err());
+        assert_eq!(&a, b"0123");
+        assert_eq!(&b, b"456789");
+    }
+
+    #[test]
+    fn gaps_and_overlaps() {
+        let mut a = [0u8; 4];
+        let mut b = [0u8; 4];
+        let mut w = ScatterWriter::new(vec![(0, &mut a[..]), (6, &mut b[..])]).unwrap();
+        assert_eq!(
+            w.write_all(b"0123456789").unwrap_err().kind(),
+            ErrorKind::WriteZero
+        );
+
+        let mut a = [0u8; 4];
+        let mut b = [0u8; 4];
+        assert!(ScatterWriter::new(vec![(0, &mut a[..]), (3, &mut b[..])]).is_err());
+    }
+}
@@ -0,0 +1,296 @@
+//! Splitting patches into small, independently checksummed segments, for
+//! transports with small message size limits, and reassembling them.
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::{collections::BTreeMap, error::Error as StdError, fmt};
+
+/// Size of the header written by [Segment::encode]: index, count and
+/// checksum, as little-endian u32s.
+pub const HEADER_LEN: usize = 12;
+
+/// A piece of a patch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    /// Position of this segment, starting at 0
+    pub index: u32,
+    /// Total number of segments the patch was split into
+    pub count: u32,
+    /// CRC-32 of `index` and `count` (as little-endian u32s), then `data`
+    pub checksum: u32,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SegmentError {
+    Truncated,
+    TooManySegments,
+    WrongChecksum {
+        index: u32,
+    },
+    WrongCount {
+        expected: u32,
+        got: u32,
+    },
+    IndexOutOfRange {
+        index: u32,
+        count: u32,
+    },
+    Missing {
+        index: u32,
+    },
+    /// Two segments with the same index, but different contents
+    Conflicting {
+        index: u32,
+    },
+    InvalidParity,
+}
+
+impl fmt::Display for SegmentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SegmentError::Truncated => write!(f, "segment shorter than its header"),
+            SegmentError::TooManySegments => write!(f, "patch needs more than 2^32 segments"),
+            SegmentError::WrongChecksum { index } => {
+                write!(f, "wrong checksum for segment {}", index)
+            }
+            SegmentError::WrongCount { expected, got } => {
+                write!(f, "wrong segment count: expected {}, got {}", expected, got)
+            }
+            SegmentError::IndexOutOfRange { index, count } => {
+                write!(f, "segment index {} out of range (count {})", index, count)
+            }
+            SegmentError::Missing { index } => write!(f, "missing segment {}", index),
+            SegmentError::Conflicting { index } => {
+                write!(f, "conflicting copies of segment {}", index)
+            }
+            SegmentError::InvalidParity => write!(f, "invalid parity segment parameters"),
+        }
+    }
+}
+
+impl StdError for SegmentError {}
+
+impl Segment {
+    /// A segment with its checksum computed
+    pub fn new(index: u32, count: u32, data: Vec<u8>) -> Self {
+        Self {
+            index,
+            count,
+            checksum: checksum(index, count, &data),
+            data,
+        }
+    }
+
+    /// Check the segment's index, count and data against its checksum
+    pub fn verify(&self) -> Result<(), SegmentError> {
+        if checksum(self.index, self.count, &self.data) != self.checksum {
+            return Err(SegmentError::WrongChecksum { index: self.index });
+        }
+        Ok(())
+    }
+
+    /// Serialize the segment as a header followed by its data
+    pub fn encode(&self) -> Vec<u8> {
+        let mut v = Vec::with_capacity(HEADER_LEN + self.data.len());
+        v.write_u32::<LittleEndian>(self.index).unwrap();
+        v.write_u32::<LittleEndian>(self.count).unwrap();
+        v.write_u32::<LittleEndian>(self.checksum).unwrap();
+        v.extend_from_slice(&self.data);
+        v
+    }
+
+    /// Deserialize a segment written by [Segment::encode], and verify it
+    pub fn decode(mut buf: &[u8]) -> Result<Self, SegmentError> {
+        if buf.len() < HEADER_LEN {
+            return Err(SegmentError::Truncated);
+        }
+        let index = buf.read_u32::<LittleEndian>().unwrap();
+        let count = buf.read_u32::<LittleEndian>().unwrap();
+        let checksum = buf.read_u32::<LittleEndian>().unwrap();
+
+        let segment = Self {
+            index,
+            count,
+            checksum,
+            data: buf.to_vec(),
+        };
+        segment.verify()?;
+        Ok(segment)
+    }
+}
+
+/// Split a patch into segments of at most `segment_size` bytes of data.
+///
+/// An empty patch still produces a single (empty) segment, so the
+/// receiving side knows it has everything.
+///
+/// # Panics
+///
+/// Panics if `segment_size` is 0.
+pub fn split_patch(patch: &[u8], segment_size: usize) -> Result<Vec<Segment>, SegmentError> {
+    assert!(segment_size > 0, "segment size cannot be 0");
+
+    let count = std::cmp::max(1, patch.len().div_ceil(segment_size));
+    if count > u32::MAX as usize {
+        return Err(SegmentError::TooManySegments);
+    }
+
+    let segments = (0..count)
+        .map(|i| {
+            let start = i * segment_size;
+            let end = std::cmp::min(start + segment_size, patch.len());
+            Segment::new(i as u32, count as u32, patch[start..end].to_vec())
+        })
+        .collect();
+    Ok(segments)
+}
+
+/// Reassemble a patch from its segments, which may be given in any order
+/// and may contain duplicates. Every segment is verified.
+pub fn reassemble<'a, I>(segments: I) -> Result<Vec<u8>, SegmentError>
+where
+    I: IntoIterator<Item = &'a Segment>,
+{
+    let mut received: BTreeMap<u32, &Segment> = BTreeMap::new();
+    let mut count = None;
+
+    for s in segments {
+        s.verify()?;
+        let expected = *count.get_or_insert(s.count);
+        if s.count != expected {
+            return Err(SegmentError::WrongCount {
+                expected,
+                got: s.count,
+            });
+        }
+        if s.index >= s.count {
+            return Err(SegmentError::IndexOutOfRange {
+                index: s.index,
+                count: s.count,
+            });
+        }
+        insert_unique(&mut received, s)?;
+    }
+
+    let mut patch = Vec::new();
+    for index in 0..count.unwrap_or(1) {
+        let s = received
+            .get(&index)
+            .ok_or(SegmentError::Missing { index })?;
+        patch.extend_from_slice(&s.data);
+    }
+    Ok(patch)
+}
+
+/// Add `s` to the segments received so far. A segment received again is
+/// fine (transports may deliver duplicates), as long as it's identical.
+pub(crate) fn insert_unique<'a>(
+    received: &mut BTreeMap<u32, &'a Segment>,
+    s: &'a Segment,
+) -> Result<(), SegmentError> {
+    match received.insert(s.index, s) {
+        Some(previous) if previous != s => Err(SegmentError::Conflicting { index: s.index }),
+        _ => Ok(()),
+    }
+}
+
+/// What [Segment::checksum] holds for a segment
+fn checksum(index: u32, count: u32, data: &[u8]) -> u32 {
+    let crc = crc32_update(!0, &index.to_le_bytes());
+    let crc = crc32_update(crc, &count.to_le_bytes());
+    !crc32_update(crc, data)
+}
+
+/// CRC-32 (IEEE 802.3), computed bitwise to avoid pulling in a dependency
+/// for something this small.
+pub fn crc32(data: &[u8]) -> u32 {
+    !crc32_update(!0, data)
+}
+
+fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn split_and_reassemble() {
+        let patch: Vec<u8> = (0..1000_u32).map(|i| (i * 7) as u8).collect();
+        let segments = split_patch(&patch, 64).unwrap();
+        assert_eq!(segments.len(), 16);
+
+        let received: Vec<Segment> = segments
+            .iter()
+            .rev()
+            .chain(segments.iter().take(3))
+            .map(|s| Segment::decode(&s.encode()).unwrap())
+            .collect();
+        assert_eq!(reassemble(&received).unwrap(), patch);
+
+        assert_eq!(
+            reassemble(&segments[1..]),
+            Err(SegmentError::Missing { index: 0 })
+        );
+
+        let mut corrupt = segments[5].encode();
+        corrupt[HEADER_LEN] ^= 1;
+        assert_eq!(
+            Segment::decode(&corrupt),
+            Err(SegmentError::WrongChecksum { index: 5 })
+        );
+    }
+
+    #[test]
+    fn corrupt_headers() {
+        let segments = split_patch(&[7; 100], 10).unwrap();
+
+        // an index or count that changed in transit is caught like data
+        let mut corrupt = segments[5].encode();
+        corrupt[0] ^= 2;
+        assert_eq!(
+            Segment::decode(&corrupt),
+            Err(SegmentError::WrongChecksum { index: 7 })
+        );
+        let mut corrupt = segments[5].encode();
+        corrupt[4] ^= 1;
+        assert_eq!(
+            Segment::decode(&corrupt),
+            Err(SegmentError::WrongChecksum { index: 5 })
+        );
+    }
+
+    #[test]
+    fn conflicting_duplicates() {
+        let segments = split_patch(&[7; 100], 10).unwrap();
+        let other = Segment::new(3, 10, vec![8; 10]);
+        let received = segments.iter().chain(Some(&other));
+        assert_eq!(
+            reassemble(received),
+            Err(SegmentError::Conflicting { index: 3 })
+        );
+    }
+
+    #[test]
+    fn empty_patch() {
+        let segments = split_patch(&[], 64).unwrap();
+        assert_eq!(segments.len(), 1);
+        assert_eq!(reassemble(&segments).unwrap(), Vec::<u8>::new());
+    }
+}
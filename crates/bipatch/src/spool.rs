@@ -0,0 +1,241 @@
+//! Applying patches when the older file is only available as a stream.
+//!
+//! [Reader](crate::Reader) needs to seek in the older file. A [Spool]
+//! wraps any `Read` and makes it seekable by keeping everything read from
+//! it so far in a backing store (memory by default, or a temporary file),
+//! up to a configurable limit:
+//!
+//! ```no_run
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let (patch, old_stream) = (std::io::empty(), std::io::empty());
+//! use bipatch::{spool::Spool, Reader};
+//!
+//! let old = Spool::new(old_stream, 512 * 1024 * 1024);
+//! let mut fresh = Reader::new(patch, old)?;
+//! std::io::copy(&mut fresh, &mut std::io::sink())?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    cmp::min,
+    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
+};
+
+/// Makes a stream seekable by spooling it into a backing store
+pub struct Spool<R, B = io::Cursor<Vec<u8>>>
+where
+    R: Read,
+    B: Read + Write + Seek,
+{
+    source: R,
+    backing: B,
+    /// number of bytes copied from `source` to `backing`
+    spooled: u64,
+    source_done: bool,
+    pos: u64,
+    limit: u64,
+}
+
+impl<R> Spool<R>
+where
+    R: Read,
+{
+    /// Spool `source` in memory, keeping at most `limit` bytes
+    pub fn new(source: R, limit: u64) -> Self {
+        Self::with_backing(source, io::Cursor::new(Vec::new()), limit)
+    }
+}
+
+impl<R, B> Spool<R, B>
+where
+    R: Read,
+    B: Read + Write + Seek,
+{
+    /// Spool `source` into `backing` (for example, a temporary file),
+    /// keeping at most `limit` bytes. `backing` should be empty.
+    pub fn with_backing(source: R, backing: B, limit: u64) -> Self {
+        Self {
+            source,
+            backing,
+            spooled: 0,
+            source_done: false,
+            pos: 0,
+            limit,
+        }
+    }
+
+    /// Copy from `source` to `backing` until at least `target` bytes are
+    /// spooled, or `source` is exhausted.
+    fn fill_to(&mut self, target: u64) -> io::Result<()> {
+        // reading one byte past the limit tells us whether the source
+        // actually has more data than we're allowed to keep
+        self.fill_to_unchecked(min(target, self.limit.saturating_add(1)))?;
+        if self.spooled > self.limit {
+            return Err(io::Error::other(format!(
+                "spool limit of {} bytes exceeded",
+                self.limit
+            )));
+        }
+        Ok(())
+    }
+
+    fn fill_to_unchecked(&mut self, target: u64) -> io::Result<()> {
+        if self.spooled >= target || self.source_done {
+            return Ok(());
+        }
+
+        let mut buf = [0u8; 8192];
+        self.backing.seek(SeekFrom::Start(self.spooled))?;
+        while self.spooled < target {
+            let want = min(buf.len() as u64, target - self.spooled) as usize;
+            let n = match self.source.read(&mut buf[..want]) {
+                Ok(0) => {
+                    self.source_done = true;
+                    break;
+                }
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            self.backing.write_all(&buf[..n])?;
+            self.spooled += n as u64;
+        }
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> (R, B) {
+        (self.source, self.backing)
+    }
+}
+
+impl<R, B> Read for Spool<R, B>
+where
+    R: Read,
+    B: Read + Write + Seek,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // seeking can put `pos` anywhere
+        self.fill_to(self.pos.saturating_add(buf.len() as u64))?;
+        if self.pos >= self.spooled {
+            return Ok(0);
+        }
+
+        let n = min(buf.len() as u64, self.spooled - self.pos) as usize;
+        self.backing.seek(SeekFrom::Start(self.pos))?;
+        self.backing.read_exact(&mut buf[..n])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R, B> Seek for Spool<R, B>
+where
+    R: Read,
+    B: Read + Write + Seek,
+{
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(n) => {
+                self.pos = n;
+                return Ok(n);
+            }
+            SeekFrom::Current(n) => (self.pos, n),
+            SeekFrom::End(n) => {
+                self.fill_to(u64::MAX)?;
+                (self.spooled, n)
+            }
+        };
+
+        let new_pos = if offset >= 0 {
+            base.checked_add(offset as u64)
+        } else {
+            base.checked_sub(offset.unsigned_abs())
+        };
+        match new_pos {
+            Some(n) => {
+                self.pos = n;
+                Ok(n)
+            }
+            None => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Reader;
+    use integer_encoding::VarIntWriter;
+
+    /// A reader that can't seek
+    struct Stream<'a>(&'a [u8]);
+
+    impl<'a> Read for Stream<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            // deliver data in small pieces, like a socket would
+            let n = min(3, buf.len());
+            self.0.read(&mut buf[..n])
+        }
+    }
+
+    #[test]
+    fn seek_and_read() {
+        let data: Vec<u8> = (0..100).collect();
+        let mut spool = Spool::new(Stream(&data), 1024);
+
+        let mut buf = [0u8; 4];
+        spool.seek(SeekFrom::Start(10)).unwrap();
+        spool.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [10, 11, 12, 13]);
+
+        spool.seek(SeekFrom::Current(-10)).unwrap();
+        spool.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [4, 5, 6, 7]);
+
+        assert_eq!(spool.seek(SeekFrom::End(-1)).unwrap(), 99);
+        assert_eq!(spool.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], 99);
+        assert_eq!(spool.read(&mut buf).unwrap(), 0);
+
+        // way past the end
+        spool.seek(SeekFrom::Start(u64::MAX)).unwrap();
+        assert_eq!(spool.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn limit() {
+        let data = vec![0u8; 100];
+        let mut spool = Spool::new(Stream(&data), 100);
+        spool.seek(SeekFrom::End(0)).unwrap();
+
+        let mut spool = Spool::new(Stream(&data), 99);
+        let mut buf = [0u8; 100];
+        assert!(spool.read_exact(&mut buf).is_err());
+    }
+
+    #[test]
+    fn apply_from_stream() {
+        let older = b"hello world";
+
+        let mut patch = crate::tests::header();
+        // skip to "world", add it, then seek back to the start for "hello"
+        for &(add_len, seek) in &[(0_u64, 6_i64), (5, -11), (5, 0)] {
+            patch.write_varint(add_len).unwrap();
+            patch.extend(std::iter::repeat_n(0, add_len as usize));
+            patch.write_varint(0_u64).unwrap();
+            crate::tests::write_seek(&mut patch, seek);
+        }
+
+        let old = Spool::new(Stream(older), 1024);
+        let mut fresh = Vec::new();
+        Reader::new(&patch[..], old)
+            .unwrap()
+            .read_to_end(&mut fresh)
+            .unwrap();
+        assert_eq!(fresh, b"worldhello");
+    }
+}
@@ -0,0 +1,111 @@
+//! Rate limiting for background patch application.
+//!
+//! Wrap the older file and/or the output in a [Throttled] so applying a
+//! patch doesn't starve interactive I/O:
+//!
+//! ```no_run
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let (patch, old, out) = (std::io::empty(), std::io::Cursor::new(vec![]), std::io::sink());
+//! use bipatch::throttle::Throttled;
+//!
+//! let old = Throttled::new(old, 4 * 1024 * 1024);
+//! let mut out = Throttled::new(out, 4 * 1024 * 1024);
+//! bipatch::apply(patch, old, &mut out)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    io::{self, Read, Seek, SeekFrom, Write},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Limits the rate of reads and writes going through `inner`
+pub struct Throttled<T> {
+    inner: T,
+    bytes_per_sec: u64,
+    start: Instant,
+    transferred: u64,
+}
+
+impl<T> Throttled<T> {
+    /// Limit `inner` to `bytes_per_sec`, averaged since creation.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes_per_sec` is 0.
+    pub fn new(inner: T, bytes_per_sec: u64) -> Self {
+        assert!(bytes_per_sec > 0, "rate limit cannot be 0");
+        Self {
+            inner,
+            bytes_per_sec,
+            start: Instant::now(),
+            transferred: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// Account for `n` transferred bytes, sleeping if we're ahead of the
+    /// allowed rate
+    fn account(&mut self, n: usize) {
+        self.transferred += n as u64;
+        let due = Duration::from_secs_f64(self.transferred as f64 / self.bytes_per_sec as f64);
+        let elapsed = self.start.elapsed();
+        if due > elapsed {
+            thread::sleep(due - elapsed);
+        }
+    }
+}
+
+impl<T: Read> Read for Throttled<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // don't let a single large read blow through the limit
+        let max = std::cmp::max(1, self.bytes_per_sec / 10) as usize;
+        let len = std::cmp::min(buf.len(), max);
+        let n = self.inner.read(&mut buf[..len])?;
+        self.account(n);
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for Throttled<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let max = std::cmp::max(1, self.bytes_per_sec / 10) as usize;
+        let len = std::cmp::min(buf.len(), max);
+        let n = self.inner.write(&buf[..len])?;
+        self.account(n);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Seek> Seek for Throttled<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_rate() {
+        let data = vec![7u8; 2000];
+        let start = Instant::now();
+
+        let mut r = Throttled::new(&data[..], 10_000);
+        let mut out = Throttled::new(Vec::new(), 10_000);
+        io::copy(&mut r, &mut out).unwrap();
+
+        assert_eq!(out.into_inner(), data);
+        assert!(start.elapsed() >= Duration::from_millis(190));
+    }
+}